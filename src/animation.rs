@@ -0,0 +1,84 @@
+/// Frames in the dino's sprite sheet, left to right: standing, two
+/// running, two ducking and dead.
+pub const DINO_FRAMES: usize = 6;
+
+/// A frame of a sprite sheet and how many seconds it stays up.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Frame {
+    pub index: usize,
+    pub duration: f64,
+}
+
+const fn frame(index: usize, duration: f64) -> Frame {
+    Frame { index, duration }
+}
+
+const STAND: &[Frame] = &[frame(0, f64::INFINITY)];
+const RUN: &[Frame] = &[frame(1, 0.1), frame(2, 0.1)];
+const DUCK: &[Frame] = &[frame(3, 0.1), frame(4, 0.1)];
+const DEAD: &[Frame] = &[frame(5, f64::INFINITY)];
+
+/// Something the dino can be seen doing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Animation {
+    /// Standing around on the title screen.
+    Idle,
+    Run,
+    Jump,
+    Duck,
+    Dead,
+}
+
+impl Animation {
+    /// The frames of the dino's sheet this plays, over and over.
+    pub fn frames(self) -> &'static [Frame] {
+        match self {
+            Animation::Idle | Animation::Jump => STAND,
+            Animation::Run => RUN,
+            Animation::Duck => DUCK,
+            Animation::Dead => DEAD,
+        }
+    }
+}
+
+/// Plays animations. Time only moves when `advance` says so, so it stays
+/// in step with the simulation.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Animator {
+    animation: Animation,
+    /// Position in `animation.frames()`.
+    position: usize,
+    /// Seconds the current frame has been up.
+    elapsed: f64,
+}
+
+impl Animator {
+    pub fn new(animation: Animation) -> Animator {
+        Animator {
+            animation,
+            position: 0,
+            elapsed: 0.0,
+        }
+    }
+
+    /// Switches to `animation` from the top, unless it's already playing.
+    pub fn play(&mut self, animation: Animation) {
+        if animation != self.animation {
+            *self = Animator::new(animation);
+        }
+    }
+
+    pub fn advance(&mut self, dt: f64) {
+        let frames = self.animation.frames();
+        self.elapsed += dt;
+        while self.elapsed >= frames[self.position].duration {
+            self.elapsed -= frames[self.position].duration;
+            self.position = (self.position + 1) % frames.len();
+        }
+    }
+
+    /// The frame of the sheet to show.
+    pub fn frame(&self) -> usize {
+        self.animation.frames()[self.position].index
+    }
+}
@@ -0,0 +1,270 @@
+use anyhow::{anyhow, Result};
+use nix::sys::{
+    time::TimeSpec,
+    timer::{Expiration, TimerSetTimeFlags},
+    timerfd::{ClockId, TimerFd, TimerFlags},
+};
+use std::{
+    fs::{self, File, OpenOptions},
+    os::{
+        fd::{AsFd, BorrowedFd},
+        unix::fs::FileExt,
+    },
+    path::Path,
+    time::{Duration, Instant},
+};
+
+pub const BACKLIGHT_ROOT: &str = "/sys/class/backlight";
+
+/// What the Touch Bar's backlight is called on T2 and Apple Silicon Macs
+/// respectively.
+const DEVICE_NAMES: [&str; 2] = ["appletb_backlight", "display-pipe"];
+
+/// How often the level moves while ramping.
+const RAMP_STEP: Duration = Duration::from_millis(16);
+
+struct Ramp {
+    from: f64,
+    to: f64,
+    start: Instant,
+    duration: Duration,
+}
+
+/// The Touch Bar's own backlight, faded from level to level, and put back
+/// to a resting level when dropped, so whoever has the bar next doesn't
+/// inherit it dimmed or off.
+///
+/// The brightness file is opened up front, since once privileges are
+/// dropped it usually can't be.
+pub struct Backlight {
+    brightness: File,
+    max: u32,
+    /// Level last written, from 0.0 to 1.0.
+    level: f64,
+    /// Level left behind on drop. Whatever it was when opened, until set.
+    resting: f64,
+    ramp: Option<Ramp>,
+    timer: TimerFd,
+}
+
+fn read_value(path: &Path) -> Result<u32> {
+    let text = fs::read_to_string(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    text.trim()
+        .parse()
+        .map_err(|e| anyhow!("{}: {}", path.display(), e))
+}
+
+impl Backlight {
+    /// Looks for the Touch Bar's device among the ones under `root`,
+    /// normally `BACKLIGHT_ROOT`.
+    pub fn find(root: &Path) -> Result<Backlight> {
+        let mut entries = fs::read_dir(root)
+            .map_err(|e| anyhow!("{}: {}", root.display(), e))?
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        let entry = entries
+            .iter()
+            .find(|entry| {
+                let name = entry.file_name();
+                let name = name.to_string_lossy();
+                DEVICE_NAMES.iter().any(|wanted| name.contains(wanted))
+            })
+            .ok_or(anyhow!("No Touch Bar backlight in {}", root.display()))?;
+        Backlight::open(&entry.path())
+    }
+
+    /// Takes over the backlight device at `path`.
+    pub fn open(path: &Path) -> Result<Backlight> {
+        let max = read_value(&path.join("max_brightness"))?;
+        if max == 0 {
+            return Err(anyhow!("{}: max_brightness is zero", path.display()));
+        }
+        let current = read_value(&path.join("brightness"))?;
+        let brightness = OpenOptions::new()
+            .write(true)
+            .open(path.join("brightness"))
+            .map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+        let timer = TimerFd::new(
+            ClockId::CLOCK_MONOTONIC,
+            TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC,
+        )?;
+        let level = (current as f64 / max as f64).min(1.0);
+        Ok(Backlight {
+            brightness,
+            max,
+            level,
+            resting: level,
+            ramp: None,
+            timer,
+        })
+    }
+
+    /// Readable whenever the ramp is due to move, at which point `tick`
+    /// wants calling.
+    pub fn fd(&self) -> BorrowedFd<'_> {
+        self.timer.as_fd()
+    }
+
+    pub fn set_resting(&mut self, level: f64) {
+        self.resting = level.clamp(0.0, 1.0);
+    }
+
+    /// Starts fading to `level`, over `seconds`. Zero goes straight there.
+    pub fn ramp_to(&mut self, level: f64, seconds: f64) -> Result<()> {
+        let level = level.clamp(0.0, 1.0);
+        if seconds <= 0.0 || level == self.level {
+            self.stop();
+            return self.write(level);
+        }
+        self.ramp = Some(Ramp {
+            from: self.level,
+            to: level,
+            start: Instant::now(),
+            duration: Duration::from_secs_f64(seconds),
+        });
+        self.timer.set(
+            Expiration::Interval(TimeSpec::from_duration(RAMP_STEP)),
+            TimerSetTimeFlags::empty(),
+        )?;
+        Ok(())
+    }
+
+    /// Moves the ramp along to wherever it should be by now.
+    pub fn tick(&mut self) -> Result<()> {
+        let _ = self.timer.wait();
+        let Some(ramp) = &self.ramp else {
+            return Ok(());
+        };
+        let done = (ramp.start.elapsed().as_secs_f64() / ramp.duration.as_secs_f64()).min(1.0);
+        let level = ramp.from + (ramp.to - ramp.from) * done;
+        if done >= 1.0 {
+            self.stop();
+        }
+        let result = self.write(level);
+        if result.is_err() {
+            // No point retrying sixty times a second.
+            self.stop();
+        }
+        result
+    }
+
+    fn stop(&mut self) {
+        self.ramp = None;
+        let _ = self.timer.unset();
+    }
+
+    fn write(&mut self, level: f64) -> Result<()> {
+        let value = (level * self.max as f64).round() as u32;
+        self.brightness
+            .write_all_at(format!("{}\n", value).as_bytes(), 0)?;
+        self.level = level;
+        Ok(())
+    }
+}
+
+impl Drop for Backlight {
+    fn drop(&mut self) {
+        self.stop();
+        if let Err(err) = self.write(self.resting) {
+            eprintln!("Failed to restore the backlight: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{path::PathBuf, thread};
+
+    /// A stand-in for `/sys/class/backlight` holding `devices`, each at
+    /// half of a max_brightness of 255.
+    fn fake_sysfs(name: &str, devices: &[&str]) -> PathBuf {
+        let root =
+            std::env::temp_dir().join(format!("dinobar-backlight-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for device in devices {
+            let dir = root.join(device);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("max_brightness"), "255\n").unwrap();
+            fs::write(dir.join("brightness"), "128\n").unwrap();
+        }
+        root
+    }
+
+    /// What was last written. Unlike a sysfs attribute, a plain file
+    /// keeps whatever longer value was there before after the first line.
+    fn brightness(root: &Path, device: &str) -> u32 {
+        let text = fs::read_to_string(root.join(device).join("brightness")).unwrap();
+        text.lines().next().unwrap().parse().unwrap()
+    }
+
+    #[test]
+    fn finds_the_touch_bar() {
+        let root = fake_sysfs(
+            "find",
+            &["acpi_video0", "appletb_backlight", "intel_backlight"],
+        );
+        let mut backlight = Backlight::find(&root).unwrap();
+        backlight.ramp_to(0.0, 0.0).unwrap();
+        assert_eq!(brightness(&root, "appletb_backlight"), 0);
+        assert_eq!(brightness(&root, "acpi_video0"), 128);
+        assert_eq!(brightness(&root, "intel_backlight"), 128);
+        fs::remove_dir_all(&root).unwrap();
+
+        let root = fake_sysfs("find-none", &["intel_backlight"]);
+        assert!(Backlight::find(&root).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn zero_ramp_writes_straight_away() {
+        let root = fake_sysfs("instant", &["appletb_backlight"]);
+        let mut backlight = Backlight::find(&root).unwrap();
+        backlight.ramp_to(0.3, 0.0).unwrap();
+        assert_eq!(brightness(&root, "appletb_backlight"), 77);
+        assert!(backlight.ramp.is_none());
+        // Out of range levels are clamped.
+        backlight.ramp_to(2.0, 0.0).unwrap();
+        assert_eq!(brightness(&root, "appletb_backlight"), 255);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn ramp_reaches_the_target() {
+        let root = fake_sysfs("ramp", &["display-pipe"]);
+        let mut backlight = Backlight::find(&root).unwrap();
+        backlight.ramp_to(0.04, 0.1).unwrap();
+        let mut seen = Vec::new();
+        for _ in 0..100 {
+            if backlight.ramp.is_none() {
+                break;
+            }
+            thread::sleep(RAMP_STEP);
+            backlight.tick().unwrap();
+            seen.push(brightness(&root, "display-pipe"));
+        }
+        assert!(backlight.ramp.is_none(), "still ramping: {:?}", seen);
+        assert_eq!(seen.last(), Some(&10));
+        // On the way down the whole time.
+        assert!(seen.windows(2).all(|pair| pair[0] >= pair[1]), "{:?}", seen);
+        assert!(seen.len() > 2, "{:?}", seen);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn drop_restores_the_resting_level() {
+        let root = fake_sysfs("drop", &["appletb_backlight"]);
+        let mut backlight = Backlight::find(&root).unwrap();
+        backlight.ramp_to(0.0, 0.0).unwrap();
+        drop(backlight);
+        // Back to what it was found at.
+        assert_eq!(brightness(&root, "appletb_backlight"), 128);
+
+        let mut backlight = Backlight::find(&root).unwrap();
+        backlight.set_resting(1.0);
+        backlight.ramp_to(0.3, 10.0).unwrap();
+        drop(backlight);
+        assert_eq!(brightness(&root, "appletb_backlight"), 255);
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+use crate::entity::{Body, Hitbox};
+
+/// Which pixels of a sprite are solid, from its bottom-left corner with `y`
+/// growing upwards.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mask {
+    width: usize,
+    height: usize,
+    /// Row by row, bottom row first.
+    solid: Vec<bool>,
+}
+
+impl Mask {
+    pub fn new(width: usize, height: usize, solid: Vec<bool>) -> Mask {
+        assert_eq!(solid.len(), width * height);
+        Mask {
+            width,
+            height,
+            solid,
+        }
+    }
+
+    /// Whether the pixel under `(x, y)` is solid. Anything off the sprite
+    /// isn't.
+    pub fn solid(&self, x: f64, y: f64) -> bool {
+        if x < 0.0 || y < 0.0 {
+            return false;
+        }
+        let (x, y) = (x as usize, y as usize);
+        x < self.width && y < self.height && self.solid[y * self.width + x]
+    }
+
+    /// The box around the solid pixels.
+    pub fn bounds(&self) -> Hitbox {
+        let (mut x1, mut y1, mut x2, mut y2) = (self.width, self.height, 0, 0);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.solid[y * self.width + x] {
+                    x1 = x1.min(x);
+                    y1 = y1.min(y);
+                    x2 = x2.max(x + 1);
+                    y2 = y2.max(y + 1);
+                }
+            }
+        }
+        if x1 >= x2 {
+            return Hitbox::new(0.0, 0.0, 0.0, 0.0);
+        }
+        Hitbox::new(x1 as f64, y1 as f64, (x2 - x1) as f64, (y2 - y1) as f64)
+    }
+}
+
+/// When, as a fraction of the move, `a` first overlaps `b` while moving by
+/// `(dx, dy)`. Boxes that already overlap meet at zero; boxes that only
+/// touch edges don't meet at all.
+pub fn sweep(a: &Hitbox, b: &Hitbox, (dx, dy): (f64, f64)) -> Option<f64> {
+    let mut enter: f64 = 0.0;
+    let mut exit: f64 = 1.0;
+    for (a1, a2, b1, b2, d) in [
+        (a.x, a.x + a.width, b.x, b.x + b.width, dx),
+        (a.y, a.y + a.height, b.y, b.y + b.height, dy),
+    ] {
+        if d == 0.0 {
+            if a2 <= b1 || b2 <= a1 {
+                return None;
+            }
+            continue;
+        }
+        let (t1, t2) = ((b1 - a2) / d, (b2 - a1) / d);
+        enter = enter.max(t1.min(t2));
+        exit = exit.min(t1.max(t2));
+    }
+    (enter < exit).then_some(enter)
+}
+
+/// Whether solid pixels of the two sprites land on each other anywhere
+/// inside both hitboxes.
+pub fn pixels_touch(a: &Mask, a_body: &Body, b: &Mask, b_body: &Body) -> bool {
+    let (ab, bb) = (a_body.bounds(), b_body.bounds());
+    let (x1, x2) = (ab.x.max(bb.x), (ab.x + ab.width).min(bb.x + bb.width));
+    let (y1, y2) = (ab.y.max(bb.y), (ab.y + ab.height).min(bb.y + bb.height));
+    // Pixel centres in world coordinates.
+    let mut y = (y1 - 0.5).ceil() + 0.5;
+    while y < y2 {
+        let mut x = (x1 - 0.5).ceil() + 0.5;
+        while x < x2 {
+            if a.solid(x - a_body.x, y - a_body.y) && b.solid(x - b_body.x, y - b_body.y) {
+                return true;
+            }
+            x += 1.0;
+        }
+        y += 1.0;
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn body(x: f64, y: f64, mask: &Mask) -> Body {
+        Body::new(x, y, mask.bounds())
+    }
+
+    #[test]
+    fn sweep_catches_tunneling() {
+        let dino = Hitbox::new(10.0, 0.0, 20.0, 40.0);
+        let cactus = Hitbox::new(50.0, 0.0, 10.0, 20.0);
+        // Eight times its own width in one tick, from clear ahead to clear
+        // behind.
+        let t = sweep(&cactus, &dino, (-80.0, 0.0)).unwrap();
+        assert_eq!(t, 0.25);
+        // Not far enough to reach.
+        assert_eq!(sweep(&cactus, &dino, (-15.0, 0.0)), None);
+        // Wrong way.
+        assert_eq!(sweep(&cactus, &dino, (80.0, 0.0)), None);
+    }
+
+    #[test]
+    fn sweep_ignores_touching_edges() {
+        let a = Hitbox::new(0.0, 0.0, 10.0, 10.0);
+        let right = Hitbox::new(10.0, 0.0, 10.0, 10.0);
+        let above = Hitbox::new(0.0, 10.0, 10.0, 10.0);
+        assert_eq!(sweep(&a, &right, (0.0, 0.0)), None);
+        // Sliding along the edge, or stopping right at it.
+        assert_eq!(sweep(&a, &right, (0.0, 25.0)), None);
+        assert_eq!(sweep(&above, &a, (-50.0, 0.0)), None);
+        assert_eq!(
+            sweep(&Hitbox::new(30.0, 0.0, 10.0, 10.0), &right, (-10.0, 0.0)),
+            None
+        );
+    }
+
+    #[test]
+    fn sweep_overlapping_meets_at_zero() {
+        let a = Hitbox::new(0.0, 0.0, 10.0, 10.0);
+        let b = Hitbox::new(5.0, 5.0, 10.0, 10.0);
+        assert_eq!(sweep(&a, &b, (0.0, 0.0)), Some(0.0));
+        assert_eq!(sweep(&a, &b, (-100.0, 3.0)), Some(0.0));
+    }
+
+    #[test]
+    fn pixels_touch_needs_solid_on_solid() {
+        // Bottom-left and top-right corners of a 4x4 box.
+        let mut solid = vec![false; 16];
+        for (x, y) in [
+            (0, 0),
+            (1, 0),
+            (0, 1),
+            (1, 1),
+            (2, 2),
+            (3, 2),
+            (2, 3),
+            (3, 3),
+        ] {
+            solid[y * 4 + x] = true;
+        }
+        let corners = Mask::new(4, 4, solid);
+        let block = Mask::new(2, 2, vec![true; 4]);
+        let a = body(0.0, 0.0, &corners);
+        // In the box but in an empty corner.
+        assert!(!pixels_touch(&corners, &a, &block, &body(2.0, 0.0, &block)));
+        assert!(!pixels_touch(&block, &body(0.0, 2.0, &block), &corners, &a));
+        // Half a pixel over a solid one.
+        assert!(pixels_touch(&corners, &a, &block, &body(1.5, 0.0, &block)));
+        assert!(pixels_touch(&corners, &a, &block, &body(3.0, 3.0, &block)));
+        // Boxes only touching.
+        assert!(!pixels_touch(&corners, &a, &block, &body(4.0, 2.0, &block)));
+    }
+}
@@ -0,0 +1,547 @@
+use crate::game::Physics;
+use anyhow::{anyhow, Result};
+use input_linux::Key;
+use nix::{
+    errno::Errno,
+    sys::inotify::{AddWatchFlags, InitFlags, Inotify, WatchDescriptor},
+};
+use serde::Deserialize;
+use std::{
+    fs,
+    io::ErrorKind,
+    os::fd::{AsFd, BorrowedFd},
+    path::{Path, PathBuf},
+};
+
+pub const SYSTEM_CONFIG_PATH: &str = "/usr/share/dinobar/config.toml";
+pub const USER_CONFIG_DIR: &str = "/etc/dinobar";
+pub const USER_CONFIG_PATH: &str = "/etc/dinobar/config.toml";
+
+pub type Color = (f64, f64, f64);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyBindings {
+    pub jump: Vec<Key>,
+    pub duck: Vec<Key>,
+    pub pause: Vec<Key>,
+    /// Switches between the game and the function row.
+    pub toggle: Vec<Key>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> KeyBindings {
+        KeyBindings {
+            jump: vec![Key::Space, Key::Up],
+            duck: vec![Key::Down],
+            pause: vec![Key::Esc],
+            toggle: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub physics: Physics,
+    pub dino_size: i32,
+    /// Height of the small cacti, alone or in a cluster.
+    pub cactus_size: i32,
+    pub large_cactus_size: i32,
+    pub pterodactyl_size: i32,
+    pub font_template: String,
+    /// Target frames per second.
+    pub frame_rate: u32,
+    pub background_color: Color,
+    pub foreground_color: Color,
+    pub ground_color: Color,
+    pub bindings: KeyBindings,
+    /// Keys shown while the bar is a function row. Empty leaves the keys to
+    /// another daemon.
+    pub function_keys: Vec<Key>,
+    /// Pressed together by a two-finger tap on the left third of the bar.
+    pub left_tap_keys: Vec<Key>,
+    /// Same for the right third.
+    pub right_tap_keys: Vec<Key>,
+    /// Seconds a finger has to rest on the bar to leave the game.
+    pub long_press_time: f64,
+    /// Who to run as once the devices are open. Empty stays root.
+    pub user: String,
+    /// Primary group to run as. Empty uses the user's.
+    pub group: String,
+    /// Supplementary groups, for reopening input devices after a restart.
+    pub groups: Vec<String>,
+    pub chroot: Option<PathBuf>,
+    /// Seconds without input before the bar dims. Zero never dims.
+    pub dim_timeout: f64,
+    /// Seconds without input before the bar switches off. Zero never does.
+    pub blank_timeout: f64,
+    /// Brightness while dimmed, from 0.0 to 1.0.
+    pub dim_brightness: f64,
+    /// Touch Bar backlight level while awake, from 0.0 to 1.0.
+    pub brightness: f64,
+    /// Seconds the backlight takes to fade between levels.
+    pub backlight_ramp_time: f64,
+    /// Furthest, in pixels, burn-in protection moves the scene. Zero keeps
+    /// it still.
+    pub pixel_shift: u32,
+    /// Swap the text colours every other burn-in step.
+    pub invert_hud: bool,
+    /// Seconds between burn-in steps.
+    pub burn_in_interval: f64,
+    /// DRM card to use instead of searching `/dev/dri`.
+    pub card: Option<PathBuf>,
+    /// Connector to use, e.g. `eDP-1`, instead of guessing by shape.
+    pub connector: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            physics: Physics::default(),
+            dino_size: 40,
+            cactus_size: 24,
+            large_cactus_size: 36,
+            pterodactyl_size: 20,
+            font_template: "Adwaita Mono".to_string(),
+            frame_rate: 60,
+            background_color: (0.0, 0.0, 0.0),
+            foreground_color: (1.0, 1.0, 1.0),
+            ground_color: (0.5, 0.5, 0.5),
+            bindings: KeyBindings::default(),
+            function_keys: vec![
+                Key::Esc,
+                Key::F1,
+                Key::F2,
+                Key::F3,
+                Key::F4,
+                Key::F5,
+                Key::F6,
+                Key::F7,
+                Key::F8,
+                Key::F9,
+                Key::F10,
+                Key::F11,
+                Key::F12,
+            ],
+            left_tap_keys: vec![Key::Esc],
+            right_tap_keys: vec![Key::F11],
+            long_press_time: 1.5,
+            user: "nobody".to_string(),
+            group: String::new(),
+            groups: vec!["input".to_string(), "video".to_string()],
+            chroot: None,
+            dim_timeout: 30.0,
+            blank_timeout: 60.0,
+            dim_brightness: 0.3,
+            brightness: 1.0,
+            backlight_ramp_time: 0.5,
+            pixel_shift: 2,
+            invert_hud: false,
+            burn_in_interval: 60.0,
+            card: None,
+            connector: None,
+        }
+    }
+}
+
+/// One config file as written on disk. Every key is optional so the user
+/// file only has to mention what it changes.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "PascalCase", deny_unknown_fields)]
+struct ConfigProxy {
+    gravity: Option<f64>,
+    jump_impulse: Option<f64>,
+    fast_fall: Option<f64>,
+    /// Milliseconds.
+    max_down_time: Option<u64>,
+    /// Milliseconds.
+    min_down_time: Option<u64>,
+    spawn_spacing_min: Option<f64>,
+    spawn_spacing_max: Option<f64>,
+    pixel_collision: Option<bool>,
+    dino_size: Option<i32>,
+    cactus_size: Option<i32>,
+    large_cactus_size: Option<i32>,
+    pterodactyl_size: Option<i32>,
+    font_template: Option<String>,
+    frame_rate: Option<u32>,
+    background_color: Option<String>,
+    foreground_color: Option<String>,
+    ground_color: Option<String>,
+    jump_keys: Option<Vec<Key>>,
+    duck_keys: Option<Vec<Key>>,
+    pause_keys: Option<Vec<Key>>,
+    toggle_keys: Option<Vec<Key>>,
+    function_keys: Option<Vec<Key>>,
+    left_tap_keys: Option<Vec<Key>>,
+    right_tap_keys: Option<Vec<Key>>,
+    /// Milliseconds.
+    long_press_time: Option<u64>,
+    user: Option<String>,
+    group: Option<String>,
+    groups: Option<Vec<String>>,
+    /// Empty means no chroot.
+    chroot: Option<String>,
+    /// Seconds.
+    dim_timeout: Option<u64>,
+    /// Seconds.
+    blank_timeout: Option<u64>,
+    dim_brightness: Option<f64>,
+    brightness: Option<f64>,
+    /// Milliseconds.
+    backlight_ramp_time: Option<u64>,
+    pixel_shift: Option<u32>,
+    invert_hud: Option<bool>,
+    /// Seconds.
+    burn_in_interval: Option<u64>,
+    /// Empty means search.
+    card: Option<String>,
+    /// Empty means guess.
+    connector: Option<String>,
+}
+
+/// Parses `#rrggbb` into cairo's 0.0 - 1.0 components.
+fn parse_color(key: &str, value: &str) -> Result<Color> {
+    let hex = value
+        .strip_prefix('#')
+        .filter(|h| h.len() == 6 && h.chars().all(|c| c.is_ascii_hexdigit()))
+        .ok_or(anyhow!(
+            "{key}: expected a colour like \"#rrggbb\", got {value:?}"
+        ))?;
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).unwrap() as f64 / 255.0;
+    Ok((channel(0), channel(2), channel(4)))
+}
+
+impl ConfigProxy {
+    fn read(path: &Path) -> Result<ConfigProxy> {
+        match fs::read_to_string(path) {
+            Ok(text) => toml::from_str(&text).map_err(|e| anyhow!("{}: {}", path.display(), e)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(ConfigProxy::default()),
+            Err(e) => Err(anyhow!("{}: {}", path.display(), e)),
+        }
+    }
+
+    fn apply(self, config: &mut Config) -> Result<()> {
+        let physics = &mut config.physics;
+        if let Some(v) = self.gravity {
+            physics.gravity = v;
+        }
+        if let Some(v) = self.jump_impulse {
+            physics.jump_impulse = v;
+        }
+        if let Some(v) = self.fast_fall {
+            physics.fast_fall = v;
+        }
+        if let Some(v) = self.max_down_time {
+            physics.max_down_time = v as f64 / 1000.0;
+        }
+        if let Some(v) = self.min_down_time {
+            physics.min_down_time = v as f64 / 1000.0;
+        }
+        if let Some(v) = self.spawn_spacing_min {
+            physics.spawn_spacing.0 = v;
+        }
+        if let Some(v) = self.spawn_spacing_max {
+            physics.spawn_spacing.1 = v;
+        }
+        if let Some(v) = self.pixel_collision {
+            physics.pixel_collision = v;
+        }
+        if let Some(v) = self.dino_size {
+            config.dino_size = v;
+        }
+        if let Some(v) = self.cactus_size {
+            config.cactus_size = v;
+        }
+        if let Some(v) = self.large_cactus_size {
+            config.large_cactus_size = v;
+        }
+        if let Some(v) = self.pterodactyl_size {
+            config.pterodactyl_size = v;
+        }
+        if let Some(v) = self.font_template {
+            config.font_template = v;
+        }
+        if let Some(v) = self.frame_rate {
+            config.frame_rate = v;
+        }
+        if let Some(v) = self.background_color {
+            config.background_color = parse_color("BackgroundColor", &v)?;
+        }
+        if let Some(v) = self.foreground_color {
+            config.foreground_color = parse_color("ForegroundColor", &v)?;
+        }
+        if let Some(v) = self.ground_color {
+            config.ground_color = parse_color("GroundColor", &v)?;
+        }
+        if let Some(v) = self.jump_keys {
+            config.bindings.jump = v;
+        }
+        if let Some(v) = self.duck_keys {
+            config.bindings.duck = v;
+        }
+        if let Some(v) = self.pause_keys {
+            config.bindings.pause = v;
+        }
+        if let Some(v) = self.toggle_keys {
+            config.bindings.toggle = v;
+        }
+        if let Some(v) = self.function_keys {
+            config.function_keys = v;
+        }
+        if let Some(v) = self.left_tap_keys {
+            config.left_tap_keys = v;
+        }
+        if let Some(v) = self.right_tap_keys {
+            config.right_tap_keys = v;
+        }
+        if let Some(v) = self.long_press_time {
+            config.long_press_time = v as f64 / 1000.0;
+        }
+        if let Some(v) = self.user {
+            config.user = v;
+        }
+        if let Some(v) = self.group {
+            config.group = v;
+        }
+        if let Some(v) = self.groups {
+            config.groups = v;
+        }
+        if let Some(v) = self.chroot {
+            config.chroot = Some(PathBuf::from(v)).filter(|p| !p.as_os_str().is_empty());
+        }
+        if let Some(v) = self.dim_timeout {
+            config.dim_timeout = v as f64;
+        }
+        if let Some(v) = self.blank_timeout {
+            config.blank_timeout = v as f64;
+        }
+        if let Some(v) = self.dim_brightness {
+            config.dim_brightness = v;
+        }
+        if let Some(v) = self.brightness {
+            config.brightness = v;
+        }
+        if let Some(v) = self.backlight_ramp_time {
+            config.backlight_ramp_time = v as f64 / 1000.0;
+        }
+        if let Some(v) = self.pixel_shift {
+            config.pixel_shift = v;
+        }
+        if let Some(v) = self.invert_hud {
+            config.invert_hud = v;
+        }
+        if let Some(v) = self.burn_in_interval {
+            config.burn_in_interval = v as f64;
+        }
+        if let Some(v) = self.card {
+            config.card = Some(PathBuf::from(v)).filter(|p| !p.as_os_str().is_empty());
+        }
+        if let Some(v) = self.connector {
+            config.connector = Some(v).filter(|c| !c.is_empty());
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    fn validate(&self) -> Result<()> {
+        let p = &self.physics;
+        let mut errors = Vec::new();
+        if !(p.gravity.is_finite() && p.gravity >= 0.0) {
+            errors.push(format!("Gravity must be zero or more, got {}", p.gravity));
+        }
+        if !(p.jump_impulse.is_finite() && p.jump_impulse > 0.0) {
+            errors.push(format!(
+                "JumpImpulse must be positive, got {}",
+                p.jump_impulse
+            ));
+        }
+        if !(p.fast_fall.is_finite() && p.fast_fall >= 0.0) {
+            errors.push(format!(
+                "FastFall must be zero or more, got {}",
+                p.fast_fall
+            ));
+        }
+        if !(p.min_down_time > 0.0 && p.min_down_time <= p.max_down_time) {
+            errors.push(format!(
+                "MinDownTime must be positive and at most MaxDownTime, got {}ms and {}ms",
+                p.min_down_time * 1000.0,
+                p.max_down_time * 1000.0
+            ));
+        }
+        let (min, max) = p.spawn_spacing;
+        if !(min.is_finite() && max.is_finite() && min > 0.0 && min < max) {
+            errors.push(format!(
+                "SpawnSpacingMin must be positive and below SpawnSpacingMax, got {min} and {max}"
+            ));
+        }
+        for (key, size) in [
+            ("DinoSize", self.dino_size),
+            ("CactusSize", self.cactus_size),
+            ("LargeCactusSize", self.large_cactus_size),
+            ("PterodactylSize", self.pterodactyl_size),
+        ] {
+            if !(1..=60).contains(&size) {
+                errors.push(format!("{key} must fit the 60px bar, got {size}"));
+            }
+        }
+        if !(1..=240).contains(&self.frame_rate) {
+            errors.push(format!(
+                "FrameRate must be between 1 and 240, got {}",
+                self.frame_rate
+            ));
+        }
+        if self.long_press_time <= 0.0 {
+            errors.push("LongPressTime must be positive".to_string());
+        }
+        // Nothing would bring the game back once it was put away.
+        if self.function_keys.is_empty() && self.bindings.toggle.is_empty() {
+            errors.push("FunctionKeys = [] needs some ToggleKeys".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.dim_brightness) {
+            errors.push(format!(
+                "DimBrightness must be between 0.0 and 1.0, got {}",
+                self.dim_brightness
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.brightness) {
+            errors.push(format!(
+                "Brightness must be between 0.0 and 1.0, got {}",
+                self.brightness
+            ));
+        }
+        if self.pixel_shift > 10 {
+            errors.push(format!(
+                "PixelShift must be at most 10, got {}",
+                self.pixel_shift
+            ));
+        }
+        if self.burn_in_interval <= 0.0 {
+            errors.push("BurnInInterval must be positive".to_string());
+        }
+        if matches!(&self.chroot, Some(root) if !root.is_absolute()) {
+            errors.push("Chroot must be an absolute path".to_string());
+        }
+        if self.font_template.trim().is_empty() {
+            errors.push("FontTemplate must not be empty".to_string());
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!("Invalid config: {}", errors.join("; ")))
+        }
+    }
+}
+
+/// Reads the system config and lays the user config on top of it.
+pub fn load_config() -> Result<Config> {
+    let mut config = Config::default();
+    ConfigProxy::read(Path::new(SYSTEM_CONFIG_PATH))?.apply(&mut config)?;
+    ConfigProxy::read(Path::new(USER_CONFIG_PATH))?.apply(&mut config)?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// Watches the user config for changes.
+///
+/// The directory is watched rather than the file, since editors usually
+/// save by writing a new file and renaming it over the old one. While the
+/// directory does not exist, its parent is watched for it to show up.
+pub struct ConfigManager {
+    inotify: Inotify,
+    watch: Option<WatchDescriptor>,
+    parent_watch: Option<WatchDescriptor>,
+}
+
+fn arm_inotify(inotify: &Inotify) -> Option<WatchDescriptor> {
+    let flags = AddWatchFlags::IN_CLOSE_WRITE
+        | AddWatchFlags::IN_MOVED_TO
+        | AddWatchFlags::IN_DELETE
+        | AddWatchFlags::IN_DELETE_SELF;
+    inotify.add_watch(USER_CONFIG_DIR, flags).ok()
+}
+
+fn arm_parent_inotify(inotify: &Inotify) -> Option<WatchDescriptor> {
+    let parent = Path::new(USER_CONFIG_DIR).parent()?;
+    let flags = AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO | AddWatchFlags::IN_ONLYDIR;
+    inotify.add_watch(parent, flags).ok()
+}
+
+impl ConfigManager {
+    pub fn new() -> ConfigManager {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC).unwrap();
+        let mut manager = ConfigManager {
+            inotify,
+            watch: None,
+            parent_watch: None,
+        };
+        manager.arm();
+        manager
+    }
+
+    fn arm(&mut self) {
+        self.watch = arm_inotify(&self.inotify);
+        if self.watch.is_some() {
+            if let Some(wd) = self.parent_watch.take() {
+                let _ = self.inotify.rm_watch(wd);
+            }
+        } else if self.parent_watch.is_none() {
+            self.parent_watch = arm_parent_inotify(&self.inotify);
+        }
+    }
+
+    /// Reloads `config` if the user file changed since the last call.
+    /// A config that fails to load is logged and the old one kept.
+    /// Returns whether `config` was replaced.
+    pub fn update(&mut self, config: &mut Config) -> bool {
+        let events = match self.inotify.read_events() {
+            Ok(events) => events,
+            Err(Errno::EAGAIN) => return false,
+            Err(err) => {
+                eprintln!("Failed to read config change events: {}", err);
+                return false;
+            }
+        };
+        let dir_name = Path::new(USER_CONFIG_DIR).file_name();
+        let file_name = Path::new(USER_CONFIG_PATH).file_name();
+        let mut changed = false;
+        for event in events {
+            if Some(event.wd) == self.parent_watch {
+                if event.name.as_deref() == dir_name {
+                    // The directory may have appeared along with a config in it.
+                    self.arm();
+                    changed = true;
+                }
+            } else if event
+                .mask
+                .intersects(AddWatchFlags::IN_DELETE_SELF | AddWatchFlags::IN_IGNORED)
+            {
+                self.arm();
+                changed = true;
+            } else if event.name.as_deref() == file_name {
+                changed = true;
+            }
+        }
+        changed && self.reload(config)
+    }
+
+    pub fn fd(&self) -> BorrowedFd<'_> {
+        self.inotify.as_fd()
+    }
+
+    /// Reloads `config` unconditionally, e.g. on SIGHUP. Same rules as
+    /// `update`.
+    pub fn reload(&self, config: &mut Config) -> bool {
+        match load_config() {
+            Ok(new) if new == *config => false,
+            Ok(new) => {
+                *config = new;
+                true
+            }
+            Err(err) => {
+                eprintln!("{}, keeping the previous config", err);
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,77 @@
+use crate::{config::KeyBindings, game::Inputs};
+use input_linux::Key;
+use std::collections::HashSet;
+
+/// Folds touch and keyboard events into the `Inputs` the simulation sees.
+pub struct Controls {
+    bindings: KeyBindings,
+    fingers: u32,
+    keys: HashSet<Key>,
+    /// A press can start and end between two ticks; remember it until a
+    /// tick has seen it.
+    jump_latched: bool,
+    pause_latched: bool,
+}
+
+impl Controls {
+    pub fn new(bindings: KeyBindings) -> Controls {
+        Controls {
+            bindings,
+            fingers: 0,
+            keys: HashSet::new(),
+            jump_latched: false,
+            pause_latched: false,
+        }
+    }
+
+    pub fn set_bindings(&mut self, bindings: KeyBindings) {
+        self.bindings = bindings;
+    }
+
+    pub fn touch_down(&mut self) {
+        self.fingers += 1;
+        self.jump_latched = true;
+    }
+
+    pub fn touch_up(&mut self) {
+        self.fingers = self.fingers.saturating_sub(1);
+    }
+
+    /// Forgets every finger, e.g. when libinput cancels the touches.
+    pub fn touch_cancel(&mut self) {
+        self.fingers = 0;
+    }
+
+    /// Fingers currently on the bar.
+    pub fn fingers(&self) -> u32 {
+        self.fingers
+    }
+
+    pub fn key(&mut self, key: Key, pressed: bool) {
+        if !pressed {
+            self.keys.remove(&key);
+            return;
+        }
+        self.keys.insert(key);
+        self.jump_latched |= self.bindings.jump.contains(&key);
+        self.pause_latched |= self.bindings.pause.contains(&key);
+    }
+
+    fn held(&self, bound: &[Key]) -> bool {
+        bound.iter().any(|k| self.keys.contains(k))
+    }
+
+    pub fn inputs(&self) -> Inputs {
+        Inputs {
+            jump: self.fingers > 0 || self.jump_latched || self.held(&self.bindings.jump),
+            duck: self.held(&self.bindings.duck),
+            pause: self.pause_latched,
+        }
+    }
+
+    /// Call once a tick has consumed `inputs`.
+    pub fn consume(&mut self) {
+        self.jump_latched = false;
+        self.pause_latched = false;
+    }
+}
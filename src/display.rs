@@ -2,19 +2,62 @@ use anyhow::{anyhow, Result};
 use drm::{
     buffer::DrmFourcc,
     control::{
-        atomic, connector,
+        atomic, connector, crtc,
         dumbbuffer::{DumbBuffer, DumbMapping},
-        framebuffer, property, AtomicCommitFlags, ClipRect, Device as ControlDevice, Mode,
-        ResourceHandle,
+        framebuffer, plane, property, AtomicCommitFlags, ClipRect, Device as ControlDevice, Event,
+        Mode, ModeTypeFlags, PlaneType, ResourceHandle, ResourceHandles,
     },
     ClientCapability, Device as DrmDevice,
 };
 use std::{
+    collections::HashMap,
     fs::{self, File, OpenOptions},
+    ops::DerefMut,
     os::unix::io::{AsFd, BorrowedFd},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
+/// Something the scene can be presented on.
+///
+/// Sizes follow the scanout orientation of the Touch Bar, which is a
+/// portrait panel: the mode is 60 pixels wide and 2170 pixels tall.
+pub trait DisplayBackend {
+    type Mapping<'a>: DerefMut<Target = [u8]>
+    where
+        Self: 'a;
+
+    /// Size of the active mode as `(width, height)`.
+    fn mode(&self) -> (u16, u16);
+    /// Size of the framebuffer as `(width, height)`; may be padded past the mode.
+    fn fb_info(&self) -> Result<(u32, u32)>;
+    /// Maps the buffer the next frame is drawn into.
+    fn map(&mut self) -> Result<Self::Mapping<'_>>;
+    /// Puts the mapped buffer on screen. `clips` is what changed since the
+    /// previous frame.
+    fn flip(&mut self, clips: &[ClipRect]) -> Result<()>;
+    /// Whether a flip has not reached the screen yet; `map` and `flip`
+    /// have to wait for it.
+    fn flip_pending(&self) -> bool;
+    /// Becomes readable when a pending flip completes.
+    fn flip_fd(&self) -> Option<BorrowedFd<'_>>;
+    /// Picks up completed flips, blocking until there is at least one.
+    fn handle_flip_events(&mut self) -> Result<()>;
+    /// Lets another program drive the display until `acquire_master`.
+    fn drop_master(&mut self) -> Result<()>;
+    /// Takes the display back and puts our framebuffer on it again.
+    fn acquire_master(&mut self) -> Result<()>;
+    /// Whether the display is still there to draw on.
+    fn connected(&self) -> bool;
+    /// Whether the display is switched on, as far as we can tell.
+    fn powered(&self) -> bool;
+    /// Switches the display off or back on, keeping the buffers.
+    fn set_active(&mut self, active: bool) -> Result<()>;
+    /// Lets go of the display and opens it again from scratch, e.g. after
+    /// the card was reset. Until this succeeds nothing else may be called
+    /// but `connected` and `reopen`.
+    fn reopen(&mut self) -> Result<()>;
+}
+
 struct Card(File);
 impl AsFd for Card {
     fn as_fd(&self) -> BorrowedFd<'_> {
@@ -35,156 +78,310 @@ impl Card {
     }
 }
 
+/// Number of buffers flipped between.
+const BUFFER_COUNT: usize = 2;
+
 pub struct DrmBackend {
     card: Card,
     mode: Mode,
-    db: DumbBuffer,
-    fb: framebuffer::Handle,
+    buffers: [(DumbBuffer, framebuffer::Handle); BUFFER_COUNT],
+    /// Index of the buffer being scanned out.
+    front: usize,
+    flip_pending: bool,
+    connector: connector::Handle,
+    crtc: crtc::Handle,
+    plane: plane::Handle,
+    connector_props: Properties,
+    crtc_props: Properties,
+    plane_props: Properties,
+    /// The card and connector asked for, to find them again on `reopen`.
+    card_path: Option<PathBuf>,
+    connector_name: Option<String>,
+    /// The buffers are gone and master is dropped for good.
+    released: bool,
 }
 
 impl Drop for DrmBackend {
     fn drop(&mut self) {
-        self.card.destroy_framebuffer(self.fb).unwrap();
-        self.card.destroy_dumb_buffer(self.db).unwrap();
+        self.release();
     }
 }
 
-fn find_prop_id<T: ResourceHandle>(
+/// The properties of a DRM object by name. Finding one takes an ioctl per
+/// property, so it's done once, up front.
+struct Properties(HashMap<String, property::Handle>);
+
+impl Properties {
+    fn new<T: ResourceHandle>(card: &Card, handle: T) -> Result<Properties> {
+        let mut props = HashMap::new();
+        for id in card.get_properties(handle)?.as_props_and_values().0 {
+            let info = card.get_property(*id)?;
+            props.insert(info.name().to_str()?.to_string(), *id);
+        }
+        Ok(Properties(props))
+    }
+
+    fn get(&self, name: &str) -> Result<property::Handle> {
+        self.0
+            .get(name)
+            .copied()
+            .ok_or(anyhow!("Property not found: {}", name))
+    }
+
+    /// Current value of property `name` of `handle`, the object these are
+    /// the properties of.
+    fn value<T: ResourceHandle>(&self, card: &Card, handle: T, name: &str) -> Result<u64> {
+        let id = self.get(name)?;
+        let props = card.get_properties(handle)?;
+        let (ids, values) = props.as_props_and_values();
+        ids.iter()
+            .zip(values)
+            .find(|(prop, _)| **prop == id)
+            .map(|(_, value)| *value)
+            .ok_or(anyhow!("Property not found: {}", name))
+    }
+}
+
+/// Name the kernel gives a connector, e.g. `eDP-1`.
+fn connector_name(info: &connector::Info) -> String {
+    format!("{}-{}", info.interface().as_str(), info.interface_id())
+}
+
+/// The preferred mode, or failing that the first one listed.
+fn pick_mode(info: &connector::Info) -> Option<Mode> {
+    let modes = info.modes();
+    modes
+        .iter()
+        .find(|m| m.mode_type().contains(ModeTypeFlags::PREFERRED))
+        .or(modes.first())
+        .copied()
+}
+
+/// A CRTC that one of the connector's encoders can drive, preferring the
+/// one already in use.
+fn pick_crtc(card: &Card, res: &ResourceHandles, info: &connector::Info) -> Result<crtc::Handle> {
+    let current = info
+        .current_encoder()
+        .and_then(|enc| card.get_encoder(enc).ok())
+        .and_then(|enc| enc.crtc());
+    let mut crtcs = Vec::new();
+    for &enc in info.encoders() {
+        crtcs.extend(res.filter_crtcs(card.get_encoder(enc)?.possible_crtcs()));
+    }
+    current
+        .filter(|crtc| crtcs.contains(crtc))
+        .or(crtcs.first().copied())
+        .ok_or(anyhow!("no CRTC reachable from its encoders"))
+}
+
+/// The primary plane of `crtc`, if it can scan out XRGB8888.
+fn pick_plane(card: &Card, res: &ResourceHandles, crtc: crtc::Handle) -> Result<plane::Handle> {
+    for handle in card.plane_handles()? {
+        let info = card.get_plane(handle)?;
+        if !res.filter_crtcs(info.possible_crtcs()).contains(&crtc) {
+            continue;
+        }
+        let kind = Properties::new(card, handle)?.value(card, handle, "type")?;
+        if kind != PlaneType::Primary as u64 {
+            continue;
+        }
+        if info.formats().contains(&(DrmFourcc::Xrgb8888 as u32)) {
+            return Ok(handle);
+        }
+    }
+    Err(anyhow!("no primary plane with XRGB8888 for its CRTC"))
+}
+
+/// Picks the connector, CRTC, plane and mode to use on `card`.
+///
+/// Without a `wanted` connector name the first connected one shaped like
+/// a Touch Bar is taken. Errors list every connector that was looked at.
+fn pick_pipeline(
     card: &Card,
-    handle: T,
-    name: &'static str,
-) -> Result<property::Handle> {
-    let props = card.get_properties(handle)?;
-    for id in props.as_props_and_values().0 {
-        let info = card.get_property(*id)?;
-        if info.name().to_str()? == name {
-            return Ok(*id);
+    wanted: Option<&str>,
+) -> Result<(connector::Handle, crtc::Handle, plane::Handle, Mode)> {
+    let res = card.resource_handles()?;
+    let mut errors = Vec::new();
+    for &handle in res.connectors() {
+        let info = card.get_connector(handle, true)?;
+        let name = connector_name(&info);
+        let attempt = || -> Result<_> {
+            if wanted.is_some_and(|w| w != name) {
+                return Err(anyhow!("not the configured connector"));
+            }
+            if info.state() != connector::State::Connected {
+                return Err(anyhow!("not connected"));
+            }
+            let mode = pick_mode(&info).ok_or(anyhow!("no modes"))?;
+            let (disp_width, disp_height) = mode.size();
+            if wanted.is_none() && disp_height / disp_width.max(1) < 30 {
+                return Err(anyhow!(
+                    "{}x{} does not look like a touchbar",
+                    disp_width,
+                    disp_height
+                ));
+            }
+            let crtc = pick_crtc(card, &res, &info)?;
+            let plane = pick_plane(card, &res, crtc)?;
+            Ok((handle, crtc, plane, mode))
+        };
+        match attempt() {
+            Ok(pipeline) => return Ok(pipeline),
+            Err(err) => errors.push(format!("{}: {}", name, err)),
         }
     }
-    Err(anyhow!("Property not found"))
+    Err(anyhow!("no usable connector ({})", errors.join("; ")))
 }
 
-fn try_open_card(path: &Path) -> Result<DrmBackend> {
+fn try_open_card(path: &Path, connector: Option<&str>) -> Result<DrmBackend> {
     let card = Card::open(path)?;
     card.set_client_capability(ClientCapability::UniversalPlanes, true)?;
     card.set_client_capability(ClientCapability::Atomic, true)?;
     card.acquire_master_lock()?;
 
-    let res = card.resource_handles()?;
-    let coninfo = res
-        .connectors()
-        .iter()
-        .flat_map(|con| card.get_connector(*con, true))
-        .collect::<Vec<_>>();
-    let crtcinfo = res
-        .crtcs()
-        .iter()
-        .flat_map(|crtc| card.get_crtc(*crtc))
-        .collect::<Vec<_>>();
+    let (connector, crtc, plane, mode) = pick_pipeline(&card, connector)?;
+    let (_, disp_height) = mode.size();
+    let fmt = DrmFourcc::Xrgb8888;
+    let buffer = || -> Result<(DumbBuffer, framebuffer::Handle)> {
+        let db = card.create_dumb_buffer((64, disp_height.into()), fmt, 32)?;
+        let fb = card.add_framebuffer(&db, 24, 32)?;
+        Ok((db, fb))
+    };
+    let buffers = [buffer()?, buffer()?];
 
-    let con = coninfo
-        .iter()
-        .find(|&i| i.state() == connector::State::Connected)
-        .ok_or(anyhow!("No connected connectors found"))?;
+    let backend = DrmBackend {
+        connector_props: Properties::new(&card, connector)?,
+        crtc_props: Properties::new(&card, crtc)?,
+        plane_props: Properties::new(&card, plane)?,
+        card,
+        mode,
+        buffers,
+        front: 0,
+        flip_pending: false,
+        connector,
+        crtc,
+        plane,
+        card_path: None,
+        connector_name: None,
+        released: false,
+    };
+    backend.modeset()?;
+    Ok(backend)
+}
 
-    let &mode = con.modes().first().ok_or(anyhow!("No modes found"))?;
-    let (disp_width, disp_height) = mode.size();
-    if disp_height / disp_width < 30 {
-        return Err(anyhow!("This does not look like a touchbar"));
+impl DrmBackend {
+    /// Frees the buffers and lets go of master. The card may already be
+    /// gone, so failures are ignored.
+    fn release(&mut self) {
+        if self.released {
+            return;
+        }
+        self.released = true;
+        for (db, fb) in self.buffers {
+            let _ = self.card.destroy_framebuffer(fb);
+            let _ = self.card.destroy_dumb_buffer(db);
+        }
+        // Fails harmlessly if we are not master at the moment.
+        let _ = self.card.release_master_lock();
     }
-    let crtc = crtcinfo.first().ok_or(anyhow!("No crtcs found"))?;
-    let fmt = DrmFourcc::Xrgb8888;
-    let db = card.create_dumb_buffer((64, disp_height.into()), fmt, 32)?;
-
-    let fb = card.add_framebuffer(&db, 24, 32)?;
-    let plane = *card
-        .plane_handles()?
-        .first()
-        .ok_or(anyhow!("No planes found"))?;
-
-    let mut atomic_req = atomic::AtomicModeReq::new();
-    atomic_req.add_property(
-        con.handle(),
-        find_prop_id(&card, con.handle(), "CRTC_ID")?,
-        property::Value::CRTC(Some(crtc.handle())),
-    );
-    let blob = card.create_property_blob(&mode)?;
-
-    atomic_req.add_property(
-        crtc.handle(),
-        find_prop_id(&card, crtc.handle(), "MODE_ID")?,
-        blob,
-    );
-    atomic_req.add_property(
-        crtc.handle(),
-        find_prop_id(&card, crtc.handle(), "ACTIVE")?,
-        property::Value::Boolean(true),
-    );
-    atomic_req.add_property(
-        plane,
-        find_prop_id(&card, plane, "FB_ID")?,
-        property::Value::Framebuffer(Some(fb)),
-    );
-    atomic_req.add_property(
-        plane,
-        find_prop_id(&card, plane, "CRTC_ID")?,
-        property::Value::CRTC(Some(crtc.handle())),
-    );
-    atomic_req.add_property(
-        plane,
-        find_prop_id(&card, plane, "SRC_X")?,
-        property::Value::UnsignedRange(0),
-    );
-    atomic_req.add_property(
-        plane,
-        find_prop_id(&card, plane, "SRC_Y")?,
-        property::Value::UnsignedRange(0),
-    );
-    atomic_req.add_property(
-        plane,
-        find_prop_id(&card, plane, "SRC_W")?,
-        property::Value::UnsignedRange((mode.size().0 as u64) << 16),
-    );
-    atomic_req.add_property(
-        plane,
-        find_prop_id(&card, plane, "SRC_H")?,
-        property::Value::UnsignedRange((mode.size().1 as u64) << 16),
-    );
-    atomic_req.add_property(
-        plane,
-        find_prop_id(&card, plane, "CRTC_X")?,
-        property::Value::SignedRange(0),
-    );
-    atomic_req.add_property(
-        plane,
-        find_prop_id(&card, plane, "CRTC_Y")?,
-        property::Value::SignedRange(0),
-    );
-    atomic_req.add_property(
-        plane,
-        find_prop_id(&card, plane, "CRTC_W")?,
-        property::Value::UnsignedRange(mode.size().0 as u64),
-    );
-    atomic_req.add_property(
-        plane,
-        find_prop_id(&card, plane, "CRTC_H")?,
-        property::Value::UnsignedRange(mode.size().1 as u64),
-    );
 
-    card.atomic_commit(AtomicCommitFlags::ALLOW_MODESET, atomic_req)?;
+    /// Points the connector, CRTC and plane at our framebuffer.
+    fn modeset(&self) -> Result<()> {
+        let card = &self.card;
+        let (con, crtc, plane) = (self.connector, self.crtc, self.plane);
+        let fb = self.buffers[self.front].1;
+        let mode = self.mode;
+        let mut atomic_req = atomic::AtomicModeReq::new();
+        atomic_req.add_property(
+            con,
+            self.connector_props.get("CRTC_ID")?,
+            property::Value::CRTC(Some(crtc)),
+        );
+        let blob = card.create_property_blob(&mode)?;
 
-    Ok(DrmBackend { card, mode, db, fb })
-}
+        atomic_req.add_property(crtc, self.crtc_props.get("MODE_ID")?, blob);
+        atomic_req.add_property(
+            crtc,
+            self.crtc_props.get("ACTIVE")?,
+            property::Value::Boolean(true),
+        );
+        atomic_req.add_property(
+            plane,
+            self.plane_props.get("FB_ID")?,
+            property::Value::Framebuffer(Some(fb)),
+        );
+        atomic_req.add_property(
+            plane,
+            self.plane_props.get("CRTC_ID")?,
+            property::Value::CRTC(Some(crtc)),
+        );
+        atomic_req.add_property(
+            plane,
+            self.plane_props.get("SRC_X")?,
+            property::Value::UnsignedRange(0),
+        );
+        atomic_req.add_property(
+            plane,
+            self.plane_props.get("SRC_Y")?,
+            property::Value::UnsignedRange(0),
+        );
+        atomic_req.add_property(
+            plane,
+            self.plane_props.get("SRC_W")?,
+            property::Value::UnsignedRange((mode.size().0 as u64) << 16),
+        );
+        atomic_req.add_property(
+            plane,
+            self.plane_props.get("SRC_H")?,
+            property::Value::UnsignedRange((mode.size().1 as u64) << 16),
+        );
+        atomic_req.add_property(
+            plane,
+            self.plane_props.get("CRTC_X")?,
+            property::Value::SignedRange(0),
+        );
+        atomic_req.add_property(
+            plane,
+            self.plane_props.get("CRTC_Y")?,
+            property::Value::SignedRange(0),
+        );
+        atomic_req.add_property(
+            plane,
+            self.plane_props.get("CRTC_W")?,
+            property::Value::UnsignedRange(mode.size().0 as u64),
+        );
+        atomic_req.add_property(
+            plane,
+            self.plane_props.get("CRTC_H")?,
+            property::Value::UnsignedRange(mode.size().1 as u64),
+        );
 
-impl DrmBackend {
-    pub fn open_card() -> Result<DrmBackend> {
+        card.atomic_commit(AtomicCommitFlags::ALLOW_MODESET, atomic_req)?;
+        Ok(())
+    }
+
+    /// Opens `card`, or the first card under `/dev/dri` with a usable
+    /// Touch Bar pipeline. `connector` names the connector to use, e.g.
+    /// `eDP-1`; without it one shaped like a Touch Bar is picked.
+    pub fn open_card(card: Option<&Path>, connector: Option<&str>) -> Result<DrmBackend> {
+        let mut backend = DrmBackend::find_card(card, connector)?;
+        backend.card_path = card.map(Path::to_path_buf);
+        backend.connector_name = connector.map(str::to_string);
+        Ok(backend)
+    }
+
+    fn find_card(card: Option<&Path>, connector: Option<&str>) -> Result<DrmBackend> {
+        if let Some(path) = card {
+            return try_open_card(path, connector)
+                .map_err(|err| anyhow!("{}: {}", path.display(), err));
+        }
         let mut errors = Vec::new();
         for entry in fs::read_dir("/dev/dri/")? {
             let entry = entry?;
             if !entry.file_name().to_string_lossy().starts_with("card") {
                 continue;
             }
-            match try_open_card(&entry.path()) {
+            match try_open_card(&entry.path(), connector) {
                 Ok(card) => return Ok(card),
                 Err(err) => errors.push(format!(
                     "{}: {}",
@@ -198,16 +395,100 @@ impl DrmBackend {
             errors.join(",\n    ")
         ))
     }
-    pub fn mode(&self) -> Mode {
-        self.mode
+}
+
+impl DisplayBackend for DrmBackend {
+    type Mapping<'a> = DumbMapping<'a>;
+
+    fn mode(&self) -> (u16, u16) {
+        self.mode.size()
+    }
+    fn fb_info(&self) -> Result<(u32, u32)> {
+        Ok(self
+            .card
+            .get_framebuffer(self.buffers[self.front].1)?
+            .size())
+    }
+    fn map(&mut self) -> Result<DumbMapping<'_>> {
+        let back = (self.front + 1) % BUFFER_COUNT;
+        Ok(self.card.map_dumb_buffer(&mut self.buffers[back].0)?)
+    }
+    // A flip replaces the whole buffer, so the clips are only needed to
+    // keep the back buffer up to date, which the caller does.
+    fn flip(&mut self, _clips: &[ClipRect]) -> Result<()> {
+        let back = (self.front + 1) % BUFFER_COUNT;
+        let mut atomic_req = atomic::AtomicModeReq::new();
+        atomic_req.add_property(
+            self.plane,
+            self.plane_props.get("FB_ID")?,
+            property::Value::Framebuffer(Some(self.buffers[back].1)),
+        );
+        self.card.atomic_commit(
+            AtomicCommitFlags::PAGE_FLIP_EVENT | AtomicCommitFlags::NONBLOCK,
+            atomic_req,
+        )?;
+        self.front = back;
+        self.flip_pending = true;
+        Ok(())
     }
-    pub fn fb_info(&self) -> Result<framebuffer::Info> {
-        Ok(self.card.get_framebuffer(self.fb)?)
+    fn flip_pending(&self) -> bool {
+        self.flip_pending
     }
-    pub fn dirty(&self, clips: &[ClipRect]) -> Result<()> {
-        Ok(self.card.dirty_framebuffer(self.fb, clips)?)
+    fn flip_fd(&self) -> Option<BorrowedFd<'_>> {
+        Some(self.card.as_fd())
+    }
+    fn handle_flip_events(&mut self) -> Result<()> {
+        for event in self.card.receive_events()? {
+            if let Event::PageFlip(_) = event {
+                self.flip_pending = false;
+            }
+        }
+        Ok(())
+    }
+    fn drop_master(&mut self) -> Result<()> {
+        Ok(self.card.release_master_lock()?)
+    }
+    fn acquire_master(&mut self) -> Result<()> {
+        self.card.acquire_master_lock()?;
+        // Whoever had the display in the meantime may have changed it.
+        self.modeset()
+    }
+    fn connected(&self) -> bool {
+        !self.released
+            && self
+                .card
+                .get_connector(self.connector, false)
+                .is_ok_and(|info| info.state() == connector::State::Connected)
+    }
+    fn powered(&self) -> bool {
+        // Unreadable properties say nothing, so count as on. DPMS 0 is on.
+        let (card, crtc, connector) = (&self.card, self.crtc, self.connector);
+        self.crtc_props
+            .value(card, crtc, "ACTIVE")
+            .map_or(true, |v| v != 0)
+            && self
+                .connector_props
+                .value(card, connector, "DPMS")
+                .map_or(true, |v| v == 0)
+    }
+    fn set_active(&mut self, active: bool) -> Result<()> {
+        if active {
+            return self.modeset();
+        }
+        let mut atomic_req = atomic::AtomicModeReq::new();
+        atomic_req.add_property(
+            self.crtc,
+            self.crtc_props.get("ACTIVE")?,
+            property::Value::Boolean(false),
+        );
+        self.card
+            .atomic_commit(AtomicCommitFlags::ALLOW_MODESET, atomic_req)?;
+        Ok(())
     }
-    pub fn map(&mut self) -> Result<DumbMapping> {
-        Ok(self.card.map_dumb_buffer(&mut self.db)?)
+    fn reopen(&mut self) -> Result<()> {
+        // Our master lock would keep us from opening the same card again.
+        self.release();
+        *self = DrmBackend::open_card(self.card_path.as_deref(), self.connector_name.as_deref())?;
+        Ok(())
     }
 }
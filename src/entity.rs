@@ -0,0 +1,135 @@
+use crate::animation::Animator;
+
+/// Sprites the scene knows how to draw.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Sprite {
+    Dino,
+    Cactus,
+    /// A few small cacti in a row.
+    CactusCluster,
+    LargeCactus,
+    Pterodactyl,
+}
+
+/// How an entity shows up on screen.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Appearance {
+    /// A frame of the sprite's sheet.
+    Sprite(Sprite, usize),
+    Solid {
+        width: f64,
+        height: f64,
+        color: (f64, f64, f64),
+    },
+}
+
+/// An axis-aligned box. `y` grows upwards from the ground.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hitbox {
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Hitbox {
+    pub fn new(x: f64, y: f64, width: f64, height: f64) -> Hitbox {
+        Hitbox {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+}
+
+/// Position and motion shared by every entity. The hitbox is relative to
+/// `(x, y)`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Body {
+    pub x: f64,
+    pub y: f64,
+    pub vx: f64,
+    pub vy: f64,
+    pub hitbox: Hitbox,
+}
+
+impl Body {
+    pub fn new(x: f64, y: f64, hitbox: Hitbox) -> Body {
+        Body {
+            x,
+            y,
+            vx: 0.0,
+            vy: 0.0,
+            hitbox,
+        }
+    }
+
+    pub fn integrate(&mut self, dt: f64) {
+        self.x += self.vx * dt;
+        self.y += self.vy * dt;
+    }
+
+    /// The hitbox in world coordinates.
+    pub fn bounds(&self) -> Hitbox {
+        Hitbox {
+            x: self.x + self.hitbox.x,
+            y: self.y + self.hitbox.y,
+            ..self.hitbox
+        }
+    }
+}
+
+pub trait Entity {
+    fn body(&self) -> &Body;
+    fn appearance(&self) -> Appearance;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Player {
+    pub body: Body,
+    pub sprite: Sprite,
+    /// Crouching on the ground, with a lower hitbox.
+    pub ducking: bool,
+    pub animator: Animator,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Obstacle {
+    pub body: Body,
+    pub sprite: Sprite,
+}
+
+/// Scenery that moves with the world but never collides.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decoration {
+    pub body: Body,
+    pub appearance: Appearance,
+}
+
+impl Entity for Player {
+    fn body(&self) -> &Body {
+        &self.body
+    }
+    fn appearance(&self) -> Appearance {
+        Appearance::Sprite(self.sprite, self.animator.frame())
+    }
+}
+
+impl Entity for Obstacle {
+    fn body(&self) -> &Body {
+        &self.body
+    }
+    fn appearance(&self) -> Appearance {
+        Appearance::Sprite(self.sprite, 0)
+    }
+}
+
+impl Entity for Decoration {
+    fn body(&self) -> &Body {
+        &self.body
+    }
+    fn appearance(&self) -> Appearance {
+        self.appearance
+    }
+}
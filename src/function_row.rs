@@ -0,0 +1,74 @@
+use input_linux::Key;
+use std::collections::HashMap;
+
+/// What a touch on the function row asks for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Press(Key),
+    Release(Key),
+    /// The button at the end of the row, back to the game.
+    Game,
+}
+
+/// The bar as a plain row of keys, for while the game is put away.
+///
+/// The buttons split the bar evenly, with one extra at the right end that
+/// brings the game back.
+pub struct FunctionRow {
+    keys: Vec<Key>,
+    width: f64,
+    /// The key each touch slot is holding down.
+    held: HashMap<u32, Key>,
+}
+
+impl FunctionRow {
+    pub fn new(keys: Vec<Key>, width: f64) -> FunctionRow {
+        FunctionRow {
+            keys,
+            width,
+            held: HashMap::new(),
+        }
+    }
+
+    /// Takes effect on the next touch; keys already held stay held.
+    pub fn set_keys(&mut self, keys: Vec<Key>) {
+        self.keys = keys;
+    }
+
+    /// Labels of the buttons, left to right.
+    pub fn labels(&self) -> impl Iterator<Item = String> + '_ {
+        self.keys
+            .iter()
+            .map(|key| format!("{:?}", key))
+            .chain(std::iter::once("DINO".to_string()))
+    }
+
+    pub fn button_width(&self) -> f64 {
+        self.width / (self.keys.len() + 1) as f64
+    }
+
+    /// With no keys configured another daemon owns the row, so touches are
+    /// left alone entirely.
+    pub fn touch_down(&mut self, slot: u32, x: f64) -> Option<Action> {
+        if self.keys.is_empty() {
+            return None;
+        }
+        let button = (x / self.button_width()).max(0.0) as usize;
+        match self.keys.get(button) {
+            Some(&key) => {
+                self.held.insert(slot, key);
+                Some(Action::Press(key))
+            }
+            None => Some(Action::Game),
+        }
+    }
+
+    pub fn touch_up(&mut self, slot: u32) -> Option<Action> {
+        self.held.remove(&slot).map(Action::Release)
+    }
+
+    /// Lets go of every held key, e.g. when the touches are cancelled.
+    pub fn release_all(&mut self) -> Vec<Key> {
+        self.held.drain().map(|(_, key)| key).collect()
+    }
+}
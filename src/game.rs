@@ -0,0 +1,595 @@
+use crate::animation::{Animation, Animator};
+use crate::collision::{pixels_touch, sweep, Mask};
+use crate::entity::{Appearance, Body, Decoration, Entity, Hitbox, Obstacle, Player, Sprite};
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    Rng, SeedableRng,
+};
+// Unlike `StdRng`, promised to give the same numbers on every platform and
+// every release, so a seed replays the same run anywhere.
+use rand_chacha::ChaCha8Rng;
+use std::collections::HashMap;
+
+/// Length of one simulation tick in seconds.
+pub const TICK: f64 = 1.0 / 120.0;
+
+const PLAYER_X_OFFSET: f64 = 10.0;
+const OBSTACLE_COUNT: usize = 20;
+/// How likely each obstacle is to come next at `SLOW_SPEED` and at
+/// `FAST_SPEED`, interpolated in between. The big stuff and the birds
+/// turn up more as the game speeds up.
+const SPAWN_WEIGHTS: [(Sprite, f64, f64); 4] = [
+    (Sprite::Cactus, 4.0, 2.0),
+    (Sprite::CactusCluster, 1.0, 2.0),
+    (Sprite::LargeCactus, 1.0, 3.0),
+    (Sprite::Pterodactyl, 0.0, 3.0),
+];
+const SLOW_SPEED: f64 = 150.0;
+const FAST_SPEED: f64 = 300.0;
+/// Heights pterodactyls fly at, as fractions of the standing dino: low
+/// enough that only a jump clears them, or high enough to duck under.
+const PTERODACTYL_HEIGHTS: [f64; 2] = [0.25, 0.75];
+/// How long the game-over screen ignores taps, so the press that was
+/// trying to save the dino does not immediately restart the game.
+const RESTART_DELAY: f64 = 0.5;
+
+/// Player input sampled for a single tick.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Inputs {
+    /// Whether the jump control (a finger on the bar) is currently held.
+    pub jump: bool,
+    /// Whether the duck control is held: crouch on the ground, dive in the air.
+    pub duck: bool,
+    /// Set for one tick to toggle between running and paused.
+    pub pause: bool,
+}
+
+/// Tunables for the simulation.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Physics {
+    pub gravity: f64,
+    pub jump_impulse: f64,
+    /// Extra downward acceleration while ducking in the air.
+    pub fast_fall: f64,
+    /// Longest press, in seconds, that still adds to the jump height.
+    pub max_down_time: f64,
+    /// Shortest press, in seconds; quicker taps jump as if held this long.
+    pub min_down_time: f64,
+    /// Range of the extra gap, in pixels, between respawned obstacles.
+    pub spawn_spacing: (f64, f64),
+    /// Only count a hit where solid pixels meet, not just the boxes
+    /// around them.
+    pub pixel_collision: bool,
+}
+
+impl Default for Physics {
+    fn default() -> Physics {
+        Physics {
+            gravity: 30.0,
+            jump_impulse: 400.0,
+            fast_fall: 1500.0,
+            max_down_time: 0.120,
+            min_down_time: 0.050,
+            spawn_spacing: (150.0, 500.0),
+            pixel_collision: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    /// Waiting for the first tap.
+    Title,
+    Running,
+    Paused,
+    /// The dino hit something after surviving `score` seconds.
+    GameOver {
+        score: f64,
+    },
+}
+
+/// The whole game simulation.
+///
+/// Nothing in here reads the clock or the thread RNG: given the same seed and
+/// the same sequence of `step` calls, two states stay identical.
+pub struct GameState {
+    pub player: Player,
+    pub obstacles: Vec<Obstacle>,
+    pub decorations: Vec<Decoration>,
+    /// Seconds survived in the current run.
+    pub time: f64,
+    pub phase: Phase,
+    pub physics: Physics,
+    ground_color: (f64, f64, f64),
+    width: f64,
+    /// Where each frame of each sprite is solid.
+    masks: HashMap<Sprite, Vec<Mask>>,
+    /// The box around each mask.
+    hitboxes: HashMap<Sprite, Vec<Hitbox>>,
+    down_time: Option<f64>,
+    /// Seconds spent in the current phase.
+    phase_time: f64,
+    /// Ignore the jump control until it is released.
+    wait_release: bool,
+    last_jump: bool,
+    rng: ChaCha8Rng,
+}
+
+impl GameState {
+    pub fn new(
+        width: f64,
+        masks: HashMap<Sprite, Vec<Mask>>,
+        physics: Physics,
+        seed: u64,
+    ) -> GameState {
+        let hitboxes = mask_bounds(&masks);
+        let mut state = GameState {
+            player: Player {
+                body: Body::new(PLAYER_X_OFFSET, 0.0, hitboxes[&Sprite::Dino][0]),
+                sprite: Sprite::Dino,
+                ducking: false,
+                animator: Animator::new(Animation::Idle),
+            },
+            obstacles: Vec::new(),
+            decorations: Vec::new(),
+            time: 0.0,
+            phase: Phase::Title,
+            physics,
+            ground_color: (0.5, 0.5, 0.5),
+            width,
+            masks,
+            hitboxes,
+            down_time: None,
+            phase_time: 0.0,
+            wait_release: false,
+            last_jump: false,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+        };
+        state.reset();
+        state
+    }
+
+    /// Puts the world back to how a run starts, without touching the phase
+    /// or the RNG.
+    fn reset(&mut self) {
+        // Just off the left edge, so they all respawn on the first tick.
+        let hitbox = self.hitboxes[&Sprite::Cactus][0];
+        let cactus = Obstacle {
+            body: Body::new(-(hitbox.x + hitbox.width), 0.0, hitbox),
+            sprite: Sprite::Cactus,
+        };
+        let ground = Decoration {
+            body: Body::new(0.0, 0.0, Hitbox::new(0.0, 0.0, self.width, 1.0)),
+            appearance: Appearance::Solid {
+                width: self.width,
+                height: 1.0,
+                color: self.ground_color,
+            },
+        };
+        self.player.ducking = false;
+        self.player.animator = Animator::new(Animation::Idle);
+        self.player.body = Body::new(PLAYER_X_OFFSET, 0.0, self.player_hitbox());
+        self.obstacles = vec![cactus; OBSTACLE_COUNT];
+        self.decorations = vec![ground];
+        self.time = 0.0;
+        self.down_time = None;
+    }
+
+    pub fn set_masks(&mut self, masks: HashMap<Sprite, Vec<Mask>>) {
+        self.hitboxes = mask_bounds(&masks);
+        self.masks = masks;
+        for obstacle in self.obstacles.iter_mut() {
+            obstacle.body.hitbox = self.hitboxes[&obstacle.sprite][0];
+        }
+        self.player.body.hitbox = self.player_hitbox();
+    }
+
+    /// The box around the frame the dino is showing.
+    fn player_hitbox(&self) -> Hitbox {
+        self.hitboxes[&Sprite::Dino][self.player.animator.frame()]
+    }
+
+    /// What the dino should be seen doing.
+    fn animation(&self) -> Animation {
+        match self.phase {
+            Phase::Title | Phase::Paused => Animation::Idle,
+            Phase::Running if self.player.ducking => Animation::Duck,
+            Phase::Running if self.player.body.y > 0.0 => Animation::Jump,
+            Phase::Running => Animation::Run,
+            Phase::GameOver { .. } => Animation::Dead,
+        }
+    }
+
+    pub fn set_ground_color(&mut self, color: (f64, f64, f64)) {
+        self.ground_color = color;
+        for decoration in self.decorations.iter_mut() {
+            if let Appearance::Solid { color: c, .. } = &mut decoration.appearance {
+                *c = color;
+            }
+        }
+    }
+
+    fn set_phase(&mut self, phase: Phase) {
+        self.phase = phase;
+        self.phase_time = 0.0;
+    }
+
+    /// Pauses a run in progress; any other phase is left alone.
+    pub fn pause(&mut self) {
+        if self.phase == Phase::Running {
+            self.set_phase(Phase::Paused);
+        }
+    }
+
+    /// Picks a paused run back up. A finger already on the bar has to lift
+    /// before it counts as a jump.
+    pub fn resume(&mut self) {
+        if self.phase == Phase::Paused {
+            self.set_phase(Phase::Running);
+            self.wait_release = true;
+        }
+    }
+
+    /// Everything on screen, back to front.
+    pub fn entities(&self) -> impl Iterator<Item = &dyn Entity> {
+        self.decorations
+            .iter()
+            .map(|e| e as &dyn Entity)
+            .chain(self.obstacles.iter().map(|e| e as &dyn Entity))
+            .chain(std::iter::once(&self.player as &dyn Entity))
+    }
+
+    /// Whether the screen can't change until the player does something.
+    pub fn is_idle(&self) -> bool {
+        match self.phase {
+            Phase::Title | Phase::Paused => true,
+            Phase::Running => false,
+            // Keep ticking until taps are accepted again.
+            Phase::GameOver { .. } => self.phase_time >= RESTART_DELAY,
+        }
+    }
+
+    /// Speed at which the ground scrolls, in pixels per second.
+    pub fn speed(&self) -> f64 {
+        150.0 * self.time.powf(1f64 / 7f64)
+    }
+
+    fn jump(&mut self, held: f64) {
+        let physics = &self.physics;
+        let body = &mut self.player.body;
+        if body.y == 0.0 {
+            let clamped =
+                held.clamp(physics.min_down_time, physics.max_down_time) / physics.max_down_time;
+            body.vy += physics.jump_impulse * clamped.powf(1f64 / 2f64);
+            body.y += 1.0;
+        }
+    }
+
+    /// Advances the simulation by `dt` seconds.
+    pub fn step(&mut self, dt: f64, inputs: &Inputs) {
+        let tapped = inputs.jump && !self.last_jump;
+        self.last_jump = inputs.jump;
+        self.phase_time += dt;
+
+        match self.phase {
+            Phase::Title => {
+                if tapped {
+                    self.start();
+                }
+            }
+            Phase::Running => {
+                if inputs.pause {
+                    self.set_phase(Phase::Paused);
+                } else {
+                    self.run(dt, inputs);
+                }
+            }
+            Phase::Paused => {
+                if inputs.pause || tapped {
+                    self.set_phase(Phase::Running);
+                    self.wait_release = tapped;
+                }
+            }
+            Phase::GameOver { .. } => {
+                if tapped && self.phase_time >= RESTART_DELAY {
+                    self.start();
+                }
+            }
+        }
+
+        // A pause freezes the dino mid-stride.
+        if self.phase != Phase::Paused {
+            self.player.animator.play(self.animation());
+            self.player.animator.advance(dt);
+            self.player.body.hitbox = self.player_hitbox();
+        }
+    }
+
+    fn start(&mut self) {
+        self.reset();
+        self.set_phase(Phase::Running);
+        self.wait_release = true;
+    }
+
+    fn run(&mut self, dt: f64, inputs: &Inputs) {
+        self.time += dt;
+
+        if self.wait_release {
+            self.wait_release = inputs.jump;
+        } else {
+            match (inputs.jump, self.down_time) {
+                (true, None) => self.down_time = Some(0.0),
+                (true, Some(held)) => {
+                    let held = held + dt;
+                    if held >= self.physics.max_down_time {
+                        self.down_time = None;
+                        self.jump(held);
+                    } else {
+                        self.down_time = Some(held);
+                    }
+                }
+                (false, Some(held)) => {
+                    self.down_time = None;
+                    self.jump(held);
+                }
+                (false, None) => {}
+            }
+        }
+
+        let body = &mut self.player.body;
+        let from_y = body.y;
+        body.vy -= self.physics.gravity * dt * body.y;
+        if inputs.duck && body.y > 0.0 {
+            body.vy -= self.physics.fast_fall * dt;
+        }
+        body.integrate(dt);
+        if body.y <= 0.0 {
+            body.y = 0.0;
+            body.vy = 0.0;
+        }
+        self.player.ducking = inputs.duck && body.y == 0.0;
+        // Collide with the frame that fits what the dino is doing now.
+        self.player.animator.play(self.animation());
+        self.player.body.hitbox = self.player_hitbox();
+
+        // Collisions are checked with the player held where it was last
+        // tick and the obstacles doing all the moving.
+        let player = Body {
+            y: from_y,
+            ..self.player.body
+        };
+        let player_dy = self.player.body.y - from_y;
+        let speed = self.speed();
+        for obstacle in self.obstacles.iter_mut() {
+            let from = obstacle.body;
+            obstacle.body.vx = -speed;
+            obstacle.body.integrate(dt);
+
+            let delta = (obstacle.body.x - from.x, -player_dy);
+            let hit = sweep(&from.bounds(), &player.bounds(), delta).is_some_and(|t| {
+                !self.physics.pixel_collision
+                    || pixels_meet(
+                        (
+                            &player,
+                            &self.masks[&Sprite::Dino][self.player.animator.frame()],
+                        ),
+                        (&from, &self.masks[&obstacle.sprite][0]),
+                        delta,
+                        t,
+                    )
+            });
+            if hit {
+                let score = self.time;
+                self.set_phase(Phase::GameOver { score });
+                return;
+            }
+        }
+
+        // Whatever went off the left edge queues up again behind the
+        // furthest obstacle, never closer than the right edge.
+        let (spacing_min, spacing_max) = self.physics.spawn_spacing;
+        let mut last = self
+            .obstacles
+            .iter()
+            .map(|obstacle| obstacle.body.x)
+            .fold(f64::NEG_INFINITY, f64::max);
+        for obstacle in self.obstacles.iter_mut() {
+            let bounds = obstacle.body.bounds();
+            if bounds.x + bounds.width <= 0.0 {
+                let sprite = pick_obstacle(&mut self.rng, speed);
+                let y = match sprite {
+                    Sprite::Pterodactyl => {
+                        let height =
+                            PTERODACTYL_HEIGHTS[self.rng.gen_range(0..PTERODACTYL_HEIGHTS.len())];
+                        self.hitboxes[&Sprite::Dino][0].height * height
+                    }
+                    _ => 0.0,
+                };
+                let x = (last + self.rng.gen_range(spacing_min..spacing_max)).max(self.width);
+                obstacle.sprite = sprite;
+                obstacle.body = Body::new(x, y, self.hitboxes[&sprite][0]);
+                last = x;
+            }
+        }
+    }
+}
+
+/// Picks the next obstacle, weighted for how fast the game is going.
+fn pick_obstacle(rng: &mut ChaCha8Rng, speed: f64) -> Sprite {
+    let t = ((speed - SLOW_SPEED) / (FAST_SPEED - SLOW_SPEED)).clamp(0.0, 1.0);
+    let weights = SPAWN_WEIGHTS.map(|(_, slow, fast)| slow + (fast - slow) * t);
+    SPAWN_WEIGHTS[WeightedIndex::new(weights).unwrap().sample(rng)].0
+}
+
+/// Whether the player's and obstacle's solid pixels meet as the obstacle
+/// moves by `delta`, from `t` of the way along to the end, a pixel at a
+/// time.
+fn pixels_meet(
+    (player, player_mask): (&Body, &Mask),
+    (obstacle, obstacle_mask): (&Body, &Mask),
+    (dx, dy): (f64, f64),
+    t: f64,
+) -> bool {
+    let steps = ((dx.abs().max(dy.abs()) * (1.0 - t)).ceil() as usize).max(1);
+    (0..=steps).any(|i| {
+        let along = t + (1.0 - t) * i as f64 / steps as f64;
+        let moved = Body {
+            x: obstacle.x + dx * along,
+            y: obstacle.y + dy * along,
+            ..*obstacle
+        };
+        pixels_touch(player_mask, player, obstacle_mask, &moved)
+    })
+}
+
+fn mask_bounds(masks: &HashMap<Sprite, Vec<Mask>>) -> HashMap<Sprite, Vec<Hitbox>> {
+    masks
+        .iter()
+        .map(|(&sprite, frames)| (sprite, frames.iter().map(Mask::bounds).collect()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::animation::DINO_FRAMES;
+
+    fn block(width: usize, height: usize) -> Mask {
+        Mask::new(width, height, vec![true; width * height])
+    }
+
+    /// Solid boxes about the size of the real sprites. A `ghost` dino is
+    /// made of nothing, so it runs through everything.
+    fn masks(ghost: bool) -> HashMap<Sprite, Vec<Mask>> {
+        let dino = if ghost {
+            Mask::new(40, 43, vec![false; 40 * 43])
+        } else {
+            block(40, 43)
+        };
+        HashMap::from([
+            (Sprite::Dino, vec![dino; DINO_FRAMES]),
+            (Sprite::Cactus, vec![block(12, 24)]),
+            (Sprite::CactusCluster, vec![block(30, 24)]),
+            (Sprite::LargeCactus, vec![block(18, 36)]),
+            (Sprite::Pterodactyl, vec![block(24, 20)]),
+        ])
+    }
+
+    /// Taps the title screen away and lets go again.
+    fn started(ghost: bool, seed: u64) -> GameState {
+        let mut state = GameState::new(2170.0, masks(ghost), Physics::default(), seed);
+        let tap = Inputs {
+            jump: true,
+            ..Default::default()
+        };
+        state.step(TICK, &tap);
+        state.step(TICK, &Inputs::default());
+        assert_eq!(state.phase, Phase::Running);
+        state
+    }
+
+    fn seconds(seconds: f64) -> usize {
+        (seconds / TICK).round() as usize
+    }
+
+    #[test]
+    fn same_seed_replays_the_same_run() {
+        let (mut a, mut b) = (started(false, 7), started(false, 7));
+        for tick in 0..seconds(30.0) {
+            // Press for a tenth of a second every two thirds of one.
+            let inputs = Inputs {
+                jump: tick % 80 < 12,
+                duck: tick % 200 > 180,
+                ..Default::default()
+            };
+            a.step(TICK, &inputs);
+            b.step(TICK, &inputs);
+            assert_eq!(a.player, b.player);
+            assert_eq!(a.obstacles, b.obstacles);
+            assert_eq!(a.phase, b.phase);
+            assert_eq!(a.time.to_bits(), b.time.to_bits());
+        }
+        let c = started(false, 8);
+        assert_ne!(started(false, 7).obstacles, c.obstacles);
+    }
+
+    #[test]
+    fn obstacle_spacing_stays_in_range() {
+        let mut state = started(true, 1);
+        let (min, max) = state.physics.spawn_spacing;
+        for _ in 0..seconds(120.0) {
+            state.step(TICK, &Inputs::default());
+            let mut xs: Vec<f64> = state.obstacles.iter().map(|o| o.body.x).collect();
+            xs.sort_by(f64::total_cmp);
+            for gap in xs.windows(2).map(|pair| pair[1] - pair[0]) {
+                assert!(
+                    gap >= min - 1e-6 && gap < max + 1e-6,
+                    "gap of {} at {}s",
+                    gap,
+                    state.time
+                );
+            }
+        }
+        assert_eq!(state.phase, Phase::Running);
+    }
+
+    #[test]
+    fn speed_ramps_up() {
+        let mut state = started(true, 1);
+        // 128 is 2 to the 7th, so the speed has doubled by then.
+        for (time, speed) in [(1.0, 150.0), (128.0, 300.0)] {
+            while state.time < time - TICK / 2.0 {
+                state.step(TICK, &Inputs::default());
+            }
+            assert!(
+                (state.speed() - speed).abs() < 0.01,
+                "{} at {}s",
+                state.speed(),
+                state.time
+            );
+        }
+    }
+
+    #[test]
+    fn full_press_jump_arc() {
+        let mut state = started(true, 1);
+        let physics = state.physics;
+        let hold = Inputs {
+            jump: true,
+            ..Default::default()
+        };
+        // Held long enough, the jump goes off without waiting for release.
+        let mut pressed = 0;
+        while state.player.body.y == 0.0 {
+            state.step(TICK, &hold);
+            pressed += 1;
+            assert!(pressed as f64 * TICK <= physics.max_down_time + 2.0 * TICK);
+        }
+        // Gravity pulls harder the higher the dino is, so the arc is half a
+        // sine wave: up to impulse / sqrt(gravity), down again after
+        // pi / sqrt(gravity) seconds.
+        let omega = physics.gravity.sqrt();
+        let mut peak: f64 = 0.0;
+        let mut airborne = 0;
+        while state.player.body.y > 0.0 {
+            state.step(TICK, &Inputs::default());
+            peak = peak.max(state.player.body.y);
+            airborne += 1;
+        }
+        let expected = physics.jump_impulse / omega;
+        assert!(
+            (peak - expected).abs() < expected * 0.02,
+            "peaked at {} instead of {}",
+            peak,
+            expected
+        );
+        let duration = airborne as f64 * TICK;
+        let expected = std::f64::consts::PI / omega;
+        assert!(
+            (duration - expected).abs() < 0.02,
+            "landed after {}s instead of {}s",
+            duration,
+            expected
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use crate::display::DisplayBackend;
+use anyhow::{anyhow, Result};
+use cairo::{Context, Format, ImageSurface, ImageSurfaceData};
+use drm::control::ClipRect;
+use std::{
+    fs::{self, File},
+    os::fd::BorrowedFd,
+    path::{Path, PathBuf},
+};
+
+/// Logical Touch Bar geometry, as the player sees it.
+pub const TOUCHBAR_WIDTH: u16 = 2170;
+pub const TOUCHBAR_HEIGHT: u16 = 60;
+
+/// Width of the dumb buffer the DRM backend allocates for the same panel.
+const FB_WIDTH: u32 = 64;
+
+/// A display backend that renders into memory instead of a DRM device.
+///
+/// The buffer mirrors what `DrmBackend` scans out, so `Scene::draw` runs
+/// unchanged. When a dump directory is set, every `flip` call writes the
+/// frame to `frame-NNNNNN.png` in landscape orientation.
+pub struct HeadlessBackend {
+    surface: ImageSurface,
+    dump_dir: Option<PathBuf>,
+    frame: u64,
+}
+
+impl HeadlessBackend {
+    pub fn new(dump_dir: Option<PathBuf>) -> Result<HeadlessBackend> {
+        if let Some(dir) = &dump_dir {
+            fs::create_dir_all(dir)?;
+        }
+        let surface = ImageSurface::create(Format::Rgb24, FB_WIDTH as i32, TOUCHBAR_WIDTH.into())?;
+        Ok(HeadlessBackend {
+            surface,
+            dump_dir,
+            frame: 0,
+        })
+    }
+
+    /// Writes the current buffer to `path` as a 2170x60 PNG.
+    pub fn dump_png(&self, path: &Path) -> Result<()> {
+        let out =
+            ImageSurface::create(Format::Rgb24, TOUCHBAR_WIDTH.into(), TOUCHBAR_HEIGHT.into())?;
+        let c = Context::new(&out)?;
+        c.translate(0.0, TOUCHBAR_HEIGHT as f64);
+        c.rotate((-90.0f64).to_radians());
+        c.set_source_surface(&self.surface, 0.0, 0.0)?;
+        c.paint()?;
+        drop(c);
+        let mut file = File::create(path)?;
+        out.write_to_png(&mut file)
+            .map_err(|e| anyhow!("Failed to write {}: {}", path.display(), e))
+    }
+}
+
+impl DisplayBackend for HeadlessBackend {
+    type Mapping<'a> = ImageSurfaceData<'a>;
+
+    fn mode(&self) -> (u16, u16) {
+        (TOUCHBAR_HEIGHT, TOUCHBAR_WIDTH)
+    }
+    fn fb_info(&self) -> Result<(u32, u32)> {
+        Ok((FB_WIDTH, TOUCHBAR_WIDTH.into()))
+    }
+    fn map(&mut self) -> Result<ImageSurfaceData<'_>> {
+        Ok(self.surface.data()?)
+    }
+    fn flip(&mut self, _clips: &[ClipRect]) -> Result<()> {
+        if let Some(dir) = &self.dump_dir {
+            self.dump_png(&dir.join(format!("frame-{:06}.png", self.frame)))?;
+        }
+        self.frame += 1;
+        Ok(())
+    }
+    // Frames land in memory as soon as they are drawn.
+    fn flip_pending(&self) -> bool {
+        false
+    }
+    fn flip_fd(&self) -> Option<BorrowedFd<'_>> {
+        None
+    }
+    fn handle_flip_events(&mut self) -> Result<()> {
+        Ok(())
+    }
+    // There is nobody to share memory with.
+    fn drop_master(&mut self) -> Result<()> {
+        Ok(())
+    }
+    fn acquire_master(&mut self) -> Result<()> {
+        Ok(())
+    }
+    fn connected(&self) -> bool {
+        true
+    }
+    fn powered(&self) -> bool {
+        true
+    }
+    fn set_active(&mut self, _active: bool) -> Result<()> {
+        Ok(())
+    }
+    fn reopen(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
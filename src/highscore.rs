@@ -0,0 +1,114 @@
+use anyhow::Result;
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+/// How many scores are kept on disk.
+pub const MAX_ENTRIES: usize = 10;
+
+const DEFAULT_STATE_DIR: &str = "/var/lib/dinobar";
+const FILE_NAME: &str = "highscores.toml";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// Seconds survived.
+    pub score: f64,
+    pub timestamp: DateTime<Local>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct HighScoreFile {
+    #[serde(default)]
+    scores: Vec<Entry>,
+}
+
+/// The best runs, highest first, backed by a TOML file.
+pub struct HighScores {
+    path: PathBuf,
+    entries: Vec<Entry>,
+}
+
+/// Directory to keep state in. systemd hands us one through
+/// `STATE_DIRECTORY` when the unit sets `StateDirectory=`.
+pub fn state_dir() -> PathBuf {
+    env::var_os("STATE_DIRECTORY")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_STATE_DIR))
+}
+
+impl HighScores {
+    /// Loads the table from the state directory. A missing or unreadable
+    /// file gives an empty table rather than an error, so a corrupt file
+    /// can never keep the game from starting.
+    pub fn load() -> HighScores {
+        let path = state_dir().join(FILE_NAME);
+        let entries = match Self::read(&path) {
+            Ok(entries) => entries,
+            Err(err) => {
+                if path.exists() {
+                    eprintln!(
+                        "Failed to load high scores from {}: {}",
+                        path.display(),
+                        err
+                    );
+                }
+                Vec::new()
+            }
+        };
+        let mut scores = HighScores { path, entries };
+        scores.normalize();
+        scores
+    }
+
+    fn read(path: &Path) -> Result<Vec<Entry>> {
+        let file: HighScoreFile = toml::from_str(&fs::read_to_string(path)?)?;
+        Ok(file.scores)
+    }
+
+    fn normalize(&mut self) {
+        self.entries.retain(|e| e.score.is_finite());
+        // Stable sort, so the older of two equal scores stays ahead.
+        self.entries.sort_by(|a, b| b.score.total_cmp(&a.score));
+        self.entries.truncate(MAX_ENTRIES);
+    }
+
+    pub fn best(&self) -> Option<f64> {
+        self.entries.first().map(|e| e.score)
+    }
+
+    /// Adds a finished run and writes the table back if it made the cut.
+    /// Returns the zero-based rank the run landed at.
+    pub fn record(&mut self, score: f64, timestamp: DateTime<Local>) -> Option<usize> {
+        self.entries.push(Entry { score, timestamp });
+        self.normalize();
+        let rank = self
+            .entries
+            .iter()
+            .position(|e| e.score == score && e.timestamp == timestamp)?;
+        if let Err(err) = self.save() {
+            eprintln!(
+                "Failed to save high scores to {}: {}",
+                self.path.display(),
+                err
+            );
+        }
+        Some(rank)
+    }
+
+    fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let file = HighScoreFile {
+            scores: self.entries.clone(),
+        };
+        // Write then rename, so a crash mid-write keeps the old table.
+        let tmp = self.path.with_extension("toml.tmp");
+        fs::write(&tmp, toml::to_string(&file)?)?;
+        fs::rename(&tmp, &self.path)?;
+        Ok(())
+    }
+}
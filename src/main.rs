@@ -2,65 +2,248 @@
  * I was drunk when I wrote this.
  */
 use anyhow::Result;
-use cairo::{Antialias, Context, FontFace, Format, ImageSurface, Pattern, Surface};
+use cairo::{Antialias, Context, FontFace, Format, ImageSurface, Surface, TextExtents};
+use chrono::Local;
 use drm::control::ClipRect;
 use fonts::FontConfig;
 use freetype::Library as FtLibrary;
 use input::{
     event::{
         device::DeviceEvent,
-        touch::{TouchEvent, TouchEventPosition},
+        keyboard::{KeyState, KeyboardEvent, KeyboardEventTrait},
+        switch::{Switch, SwitchEvent, SwitchState},
+        touch::{TouchEvent, TouchEventPosition, TouchEventSlot},
         Event, EventTrait,
     },
     Device as InputDevice, Libinput, LibinputInterface,
 };
-use libc::{c_char, O_ACCMODE, O_RDONLY, O_RDWR, O_WRONLY};
-use nix::sys::{
-    epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags},
-    signal::{SigSet, Signal},
+use input_linux::Key;
+use libc::{O_ACCMODE, O_RDONLY, O_RDWR, O_WRONLY};
+use nix::{
+    errno::Errno,
+    sys::{
+        epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout},
+        signal::{SigSet, Signal},
+        signalfd::{SfdFlags, SignalFd},
+        time::TimeSpec,
+        timer::{Expiration, TimerSetTimeFlags},
+        timerfd::{ClockId, TimerFd, TimerFlags},
+    },
 };
 use rand::Rng;
 use std::{
+    collections::HashMap,
     fs::{File, OpenOptions},
     io::Read,
     os::{
-        fd::AsFd,
+        fd::{AsFd, AsRawFd, BorrowedFd},
         unix::{fs::OpenOptionsExt, io::OwnedFd},
     },
     panic::{self, AssertUnwindSafe},
-    path::Path,
-    thread,
+    path::{Path, PathBuf},
     time::{Duration, Instant},
 };
 
+mod animation;
+mod backlight;
+mod collision;
+mod config;
+mod controls;
 mod display;
+mod entity;
 mod fonts;
+mod function_row;
+mod game;
+mod headless;
+mod highscore;
+mod privileges;
+mod shortcuts;
+mod suspend;
+mod uinput;
 
-use display::DrmBackend;
+use animation::DINO_FRAMES;
+use backlight::{Backlight, BACKLIGHT_ROOT};
+use collision::Mask;
+use config::{load_config, Color, Config, ConfigManager};
+use controls::Controls;
+use display::{DisplayBackend, DrmBackend};
+use entity::{Appearance, Entity, Sprite};
+use function_row::{Action, FunctionRow};
+use game::{GameState, Inputs, Phase, TICK};
+use headless::HeadlessBackend;
+use highscore::HighScores;
+use privileges::{drop_privileges, prepare_dirs};
+use shortcuts::Shortcuts;
+use suspend::SuspendDetector;
+use uinput::VirtualKeyboard;
 
-fn try_load_png<R>(mut data: R, icon_size: i32) -> Result<ImageSurface>
+/// Loads a sprite sheet of `frames` equally wide frames side by side, each
+/// scaled to `icon_size` pixels tall, keeping its shape.
+fn try_load_png<R>(mut data: R, icon_size: i32, frames: usize) -> Result<Vec<ImageSurface>>
 where
     R: Read,
 {
-    let surf = ImageSurface::create_from_png(&mut data)?;
-    if surf.height() == icon_size && surf.width() == icon_size {
-        return Ok(surf);
-    }
-    let resized = ImageSurface::create(Format::ARgb32, icon_size, icon_size).unwrap();
-    let c = Context::new(&resized).unwrap();
-    c.scale(
-        icon_size as f64 / surf.width() as f64,
-        icon_size as f64 / surf.height() as f64,
-    );
-    c.set_source_surface(surf, 0.0, 0.0).unwrap();
-    c.set_antialias(Antialias::Best);
-    c.paint().unwrap();
-    Ok(resized)
+    let sheet = ImageSurface::create_from_png(&mut data)?;
+    if frames == 1 && sheet.height() == icon_size {
+        return Ok(vec![sheet]);
+    }
+    let frame_width = sheet.width() as f64 / frames as f64;
+    let scale = icon_size as f64 / sheet.height() as f64;
+    let width = ((frame_width * scale).round() as i32).max(1);
+    (0..frames)
+        .map(|i| {
+            // Cut out first, so scaling doesn't smear the neighbours in.
+            let source = sheet.create_for_rectangle(cairo::Rectangle::new(
+                i as f64 * frame_width,
+                0.0,
+                frame_width,
+                sheet.height() as f64,
+            ))?;
+            let resized = ImageSurface::create(Format::ARgb32, width, icon_size)?;
+            let c = Context::new(&resized)?;
+            c.scale(width as f64 / frame_width, scale);
+            c.set_source_surface(&source, 0.0, 0.0)?;
+            c.set_antialias(Antialias::Best);
+            c.paint()?;
+            drop(c);
+            Ok(resized)
+        })
+        .collect()
 }
 
 pub struct Scene {
+    /// Every frame of every sprite sheet.
+    sprites: HashMap<Sprite, Vec<ImageSurface>>,
     drawables: Vec<Drawable>,
+    /// Drawables that moved or went away since the last draw, as they were
+    /// last drawn.
+    stale: Vec<Drawable>,
+    /// Text drawn last frame.
+    labels: Vec<Label>,
+    /// Repaint everything on the next draw.
+    full_redraw: bool,
     fontface: FontFace,
+    background_color: Color,
+    foreground_color: Color,
+    /// 1.0 is as configured, lower darkens everything.
+    brightness: f64,
+    burn_in: BurnIn,
+}
+
+/// Keeps the OLED from wearing the same pixels all the time: every so
+/// often the whole scene moves by a pixel or so, and the text swaps its
+/// colours.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct BurnIn {
+    /// Furthest the scene moves, in pixels, either way sideways or up.
+    pixel_shift: u32,
+    invert_hud: bool,
+    step: u32,
+}
+
+impl BurnIn {
+    fn new(config: &Config) -> BurnIn {
+        BurnIn {
+            pixel_shift: config.pixel_shift,
+            invert_hud: config.invert_hud,
+            step: 0,
+        }
+    }
+
+    /// Walks the offsets row by row, one spot per step. Never down: the
+    /// ground is the bottom row of the bar, with nowhere lower to go.
+    fn offset(&self) -> (f64, f64) {
+        let side = 2 * self.pixel_shift + 1;
+        let spot = self.step % (side * (self.pixel_shift + 1));
+        (
+            (spot % side) as f64 - self.pixel_shift as f64,
+            -((spot / side) as f64),
+        )
+    }
+
+    fn inverted(&self) -> bool {
+        self.invert_hud && self.step % 2 == 1
+    }
+}
+
+/// A rectangle in the landscape coordinates the scene is drawn in, with `y`
+/// growing downwards from the top of the bar.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Rect {
+    x: f64,
+    y: f64,
+    width: f64,
+    height: f64,
+}
+
+impl Rect {
+    fn translate(self, (dx, dy): (f64, f64)) -> Rect {
+        Rect {
+            x: self.x + dx,
+            y: self.y + dy,
+            ..self
+        }
+    }
+
+    /// Grows the rect out to whole pixels, plus one for antialiasing.
+    fn expand(self) -> Rect {
+        let x = self.x.floor() - 1.0;
+        let y = self.y.floor() - 1.0;
+        Rect {
+            x,
+            y,
+            width: (self.x + self.width).ceil() + 1.0 - x,
+            height: (self.y + self.height).ceil() + 1.0 - y,
+        }
+    }
+
+    /// The same area on the portrait scanout, clamped to the screen.
+    fn clip_rect(self, width: i32, height: i32) -> Option<ClipRect> {
+        let x1 = (height as f64 - (self.y + self.height)).max(0.0);
+        let x2 = (height as f64 - self.y).min(height as f64);
+        let y1 = self.x.max(0.0);
+        let y2 = (self.x + self.width).min(width as f64);
+        if x1 >= x2 || y1 >= y2 {
+            return None;
+        }
+        Some(ClipRect::new(x1 as u16, y1 as u16, x2 as u16, y2 as u16))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Label {
+    text: String,
+    size: f64,
+    /// Where the text starts, on the baseline.
+    origin: (f64, f64),
+    bounds: Rect,
+}
+
+impl Label {
+    /// `place` picks the origin given the size of the text.
+    fn new(
+        c: &Context,
+        text: String,
+        size: f64,
+        place: impl FnOnce(&TextExtents) -> (f64, f64),
+    ) -> Label {
+        c.set_font_size(size);
+        let extents = c.text_extents(&text).unwrap();
+        let origin = place(&extents);
+        let bounds = Rect {
+            x: origin.0 + extents.x_bearing(),
+            y: origin.1 + extents.y_bearing(),
+            width: extents.width(),
+            height: extents.height(),
+        }
+        .expand();
+        Label {
+            text,
+            size,
+            origin,
+            bounds,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -93,66 +276,284 @@ impl Drawable {
             surface,
         }
     }
+
+    fn bounds(&self, height: i32) -> Rect {
+        Rect {
+            x: self.x,
+            y: height as f64 - self.y - self.height,
+            width: self.width,
+            height: self.height,
+        }
+        .expand()
+    }
 }
 
-impl Scene {
-    fn new(dino: ImageSurface, cactus: ImageSurface) -> Scene {
-        let mut drawables = vec![Drawable::new(
-            0.0,
-            0.0,
-            9.0,
-            9.0,
-            (1.0, 1.0, 1.0),
-            Some(dino),
-        )];
-
-        let w = cactus.width() as f64;
-        let h = cactus.height() as f64;
-        let value = Some(cactus);
-        for _i in 0..20 {
-            drawables.push(Drawable {
-                x: -w,
-                y: 0.0,
-                width: w,
-                height: h,
-                color: (0.0, 1.0, 0.0),
-                needs_redraw: false,
-                surface: value.clone(),
-            });
-        }
-
-        let fc = FontConfig::new();
-        let mut pt = fonts::Pattern::new("Adwaita Mono");
-        fc.perform_substitutions(&mut pt);
-        let pat_match = match fc.match_pattern(&pt) {
+fn load_sprites(config: &Config) -> HashMap<Sprite, Vec<ImageSurface>> {
+    let dino_png = include_bytes!("dino_sheet.png");
+    let cactus_png = include_bytes!("cactus.png");
+    let cactus_cluster_png = include_bytes!("cactus_cluster.png");
+    let large_cactus_png = include_bytes!("large_cactus.png");
+    let pterodactyl_png = include_bytes!("pterodactyl.png");
+    HashMap::from([
+        (
+            Sprite::Dino,
+            try_load_png(&dino_png[..], config.dino_size, DINO_FRAMES).unwrap(),
+        ),
+        (
+            Sprite::Cactus,
+            try_load_png(&cactus_png[..], config.cactus_size, 1).unwrap(),
+        ),
+        (
+            Sprite::CactusCluster,
+            try_load_png(&cactus_cluster_png[..], config.cactus_size, 1).unwrap(),
+        ),
+        (
+            Sprite::LargeCactus,
+            try_load_png(&large_cactus_png[..], config.large_cactus_size, 1).unwrap(),
+        ),
+        (
+            Sprite::Pterodactyl,
+            try_load_png(&pterodactyl_png[..], config.pterodactyl_size, 1).unwrap(),
+        ),
+    ])
+}
+
+/// Which pixels of `surface` are solid enough to bump into.
+fn sprite_mask(surface: &ImageSurface) -> Mask {
+    let (width, height) = (surface.width() as usize, surface.height() as usize);
+    let stride = surface.stride() as usize;
+    let mut solid = Vec::with_capacity(width * height);
+    surface
+        .with_data(|data| {
+            // Masks go bottom row first.
+            for y in (0..height).rev() {
+                for x in 0..width {
+                    let i = y * stride + x * 4;
+                    let pixel = u32::from_ne_bytes(data[i..i + 4].try_into().unwrap());
+                    solid.push(pixel >> 24 > 127);
+                }
+            }
+        })
+        .unwrap();
+    Mask::new(width, height, solid)
+}
+
+fn load_font(template: &str) -> FontFace {
+    let fc = FontConfig::new();
+    let mut pt = fonts::Pattern::new(template);
+    fc.perform_substitutions(&mut pt);
+    let pat_match = match fc.match_pattern(&pt) {
         Ok(pat) => pat,
         Err(_) => panic!("Unable to find specified font. If you are using the default config, make sure you have at least one font installed")
     };
-        let file_name = pat_match.get_file_name();
-        let file_idx = pat_match.get_font_index();
-        let ft_library = FtLibrary::init().unwrap();
-        let face = ft_library.new_face(file_name, file_idx).unwrap();
-        let fontface = FontFace::create_from_ft(&face).unwrap();
+    let file_name = pat_match.get_file_name();
+    let file_idx = pat_match.get_font_index();
+    let ft_library = FtLibrary::init().unwrap();
+    let face = ft_library.new_face(file_name, file_idx).unwrap();
+    FontFace::create_from_ft(&face).unwrap()
+}
 
+impl Scene {
+    fn new(config: &Config) -> Scene {
         Scene {
-            drawables,
-            fontface,
+            sprites: load_sprites(config),
+            drawables: Vec::new(),
+            stale: Vec::new(),
+            labels: Vec::new(),
+            full_redraw: true,
+            fontface: load_font(&config.font_template),
+            background_color: config.background_color,
+            foreground_color: config.foreground_color,
+            brightness: 1.0,
+            burn_in: BurnIn::new(config),
+        }
+    }
+
+    /// Picks up sprite sizes, font and colours from a reloaded config.
+    fn apply_config(&mut self, config: &Config) {
+        self.sprites = load_sprites(config);
+        self.fontface = load_font(&config.font_template);
+        self.background_color = config.background_color;
+        self.foreground_color = config.foreground_color;
+        self.drawables.clear();
+        self.burn_in = BurnIn {
+            step: self.burn_in.step,
+            ..BurnIn::new(config)
+        };
+        self.invalidate();
+    }
+
+    /// Moves on to the next burn-in offset and colours.
+    fn shift_burn_in(&mut self) {
+        self.burn_in.step = self.burn_in.step.wrapping_add(1);
+        self.invalidate();
+    }
+
+    fn set_brightness(&mut self, brightness: f64) {
+        if brightness != self.brightness {
+            self.brightness = brightness;
+            self.invalidate();
+        }
+    }
+
+    /// Makes the next draw repaint the whole bar, e.g. after someone else
+    /// had the display.
+    fn invalidate(&mut self) {
+        self.full_redraw = true;
+    }
+
+    /// Where each sprite is solid.
+    fn masks(&self) -> HashMap<Sprite, Vec<Mask>> {
+        self.sprites
+            .iter()
+            .map(|(&sprite, frames)| (sprite, frames.iter().map(sprite_mask).collect()))
+            .collect()
+    }
+
+    fn drawable(&self, entity: &dyn Entity) -> Drawable {
+        let body = entity.body();
+        match entity.appearance() {
+            Appearance::Sprite(sprite, frame) => {
+                let surface = &self.sprites[&sprite][frame];
+                Drawable::new(
+                    body.x,
+                    body.y,
+                    surface.width() as f64,
+                    surface.height() as f64,
+                    (1.0, 1.0, 1.0),
+                    Some(surface.clone()),
+                )
+            }
+            Appearance::Solid {
+                width,
+                height,
+                color,
+            } => Drawable::new(body.x, body.y, width, height, color, None),
+        }
+    }
+
+    /// Brings the drawables in line with the entities in `state`.
+    fn sync(&mut self, state: &GameState) {
+        let mut count = 0;
+        for entity in state.entities() {
+            let next = self.drawable(entity);
+            match self.drawables.get_mut(count) {
+                Some(prev)
+                    if prev.x == next.x
+                        && prev.y == next.y
+                        && prev.width == next.width
+                        && prev.height == next.height
+                        && prev.color == next.color
+                        && prev.surface.as_ref().map(|s| s.to_raw_none())
+                            == next.surface.as_ref().map(|s| s.to_raw_none()) => {}
+                Some(prev) => self.stale.push(std::mem::replace(prev, next)),
+                None => self.drawables.push(next),
+            }
+            count += 1;
+        }
+        if count < self.drawables.len() {
+            self.stale.extend(self.drawables.drain(count..));
+        }
+    }
+
+    /// Lays out the score and banner for this frame.
+    fn labels(
+        &self,
+        c: &Context,
+        width: i32,
+        height: i32,
+        state: &GameState,
+        best: Option<f64>,
+    ) -> Vec<Label> {
+        let mut labels = Vec::new();
+
+        let mut hud = Vec::new();
+        if let Some(best) = best {
+            hud.push(format!("HI {:.1}s", best));
+        }
+        if state.phase != Phase::Title {
+            hud.push(format!("{:.1}s", state.time));
         }
+        if !hud.is_empty() {
+            // Far enough in that the burn-in shift doesn't push it off the edge.
+            let margin = self.burn_in.pixel_shift as f64;
+            labels.push(Label::new(c, hud.join("  "), 12.0, |e| {
+                (margin, e.height() + margin)
+            }));
+        }
+
+        let banner = match state.phase {
+            Phase::Title => Some("DINOBAR - tap to start".to_string()),
+            Phase::Running => None,
+            Phase::Paused => Some("PAUSED - tap to resume".to_string()),
+            Phase::GameOver { score } => {
+                Some(format!("GAME OVER - {:.1}s - tap to restart", score))
+            }
+        };
+        if let Some(banner) = banner {
+            labels.push(Label::new(c, banner, 20.0, |e| {
+                (
+                    (width as f64 - e.width()) / 2.0 - e.x_bearing(),
+                    (height as f64 - e.height()) / 2.0 - e.y_bearing(),
+                )
+            }));
+        }
+        labels
     }
 
+    /// Repaints whatever changed since the last draw and returns where that
+    /// was, in scanout coordinates.
     fn draw(
         &mut self,
         width: i32,
         height: i32,
         surface: &Surface,
-        time: &TimeStep,
+        state: &GameState,
+        best: Option<f64>,
     ) -> Vec<ClipRect> {
         let c = Context::new(surface).unwrap();
-        let modified_regions = Vec::new();
         c.translate(height as f64, 0.0);
         c.rotate((90.0f64).to_radians());
+        let offset = self.burn_in.offset();
+        c.translate(offset.0, offset.1);
+        c.set_font_face(&self.fontface);
 
-        c.set_source_rgb(0.0, 0.0, 0.0);
+        let labels = self.labels(&c, width, height, state, best);
+        let mut damage: Vec<Rect> = if self.full_redraw {
+            vec![Rect {
+                x: -offset.0,
+                y: -offset.1,
+                width: width as f64,
+                height: height as f64,
+            }]
+        } else {
+            self.stale
+                .iter()
+                .chain(self.drawables.iter().filter(|d| d.needs_redraw))
+                .map(|d| d.bounds(height))
+                .collect()
+        };
+        if labels != self.labels {
+            damage.extend(self.labels.iter().chain(labels.iter()).map(|l| l.bounds));
+        }
+        self.full_redraw = false;
+        self.stale.clear();
+
+        let modified_regions: Vec<ClipRect> = damage
+            .iter()
+            .filter_map(|r| r.translate(offset).clip_rect(width, height))
+            .collect();
+        if modified_regions.is_empty() {
+            self.labels = labels;
+            return modified_regions;
+        }
+        for r in &damage {
+            c.rectangle(r.x, r.y, r.width, r.height);
+        }
+        c.clip();
+
+        let (r, g, b) = self.background_color;
+        c.set_source_rgb(r, g, b);
         c.paint().unwrap();
 
         for drawable in self.drawables.iter_mut() {
@@ -172,18 +573,60 @@ impl Scene {
             drawable.needs_redraw = false;
         }
 
-        let timer_text = format!("{:.1}s", time.start_time.elapsed().as_secs_f64());
-
-        c.set_font_face(&self.fontface);
-        c.set_font_size(12.0);
+        let (mut fg, mut bg) = (self.foreground_color, self.background_color);
+        if self.burn_in.inverted() {
+            (fg, bg) = (bg, fg);
+        }
+        for label in &labels {
+            if self.burn_in.inverted() {
+                let b = label.bounds;
+                c.set_source_rgb(bg.0, bg.1, bg.2);
+                c.rectangle(b.x, b.y, b.width, b.height);
+                c.fill().unwrap();
+            }
+            c.set_source_rgb(fg.0, fg.1, fg.2);
+            c.set_font_size(label.size);
+            c.move_to(label.origin.0, label.origin.1);
+            c.show_text(&label.text).unwrap();
+        }
+        self.labels = labels;
 
-        let extends = c.text_extents(&timer_text).unwrap();
-        c.move_to(0.0, extends.height());
-        c.set_source_rgb(1.0, 1.0, 1.0);
-        c.show_text(&timer_text).unwrap();
+        if self.brightness < 1.0 {
+            c.set_source_rgba(0.0, 0.0, 0.0, 1.0 - self.brightness);
+            c.paint().unwrap();
+        }
 
         modified_regions
     }
+
+    fn draw_function_row(&self, height: i32, surface: &Surface, row: &FunctionRow) {
+        let c = Context::new(surface).unwrap();
+        c.translate(height as f64, 0.0);
+        c.rotate((90.0f64).to_radians());
+
+        let (r, g, b) = self.background_color;
+        c.set_source_rgb(r, g, b);
+        c.paint().unwrap();
+
+        c.set_font_face(&self.fontface);
+        c.set_font_size(20.0);
+        let (r, g, b) = self.foreground_color;
+        c.set_source_rgb(r, g, b);
+        let button_width = row.button_width();
+        for (i, label) in row.labels().enumerate() {
+            let left = i as f64 * button_width;
+            if i > 0 {
+                c.rectangle(left, height as f64 * 0.2, 1.0, height as f64 * 0.6);
+                c.fill().unwrap();
+            }
+            let extents = c.text_extents(&label).unwrap();
+            c.move_to(
+                left + (button_width - extents.width()) / 2.0 - extents.x_bearing(),
+                (height as f64 - extents.height()) / 2.0 - extents.y_bearing(),
+            );
+            c.show_text(&label).unwrap();
+        }
+    }
 }
 
 struct Interface;
@@ -208,7 +651,6 @@ impl LibinputInterface for Interface {
 #[derive(Debug)]
 pub struct TimeStep {
     last_time: Instant,
-    start_time: Instant,
 }
 
 impl Default for TimeStep {
@@ -221,10 +663,14 @@ impl TimeStep {
     pub fn new() -> TimeStep {
         TimeStep {
             last_time: Instant::now(),
-            start_time: Instant::now(),
         }
     }
 
+    /// Forgets the time since the last call, e.g. after sitting idle.
+    pub fn reset(&mut self) {
+        self.last_time = Instant::now();
+    }
+
     pub fn delta(&mut self) -> f64 {
         let current_time = Instant::now();
         let delta = current_time.duration_since(self.last_time).as_secs_f64();
@@ -234,26 +680,474 @@ impl TimeStep {
 }
 
 fn main() {
-    let mut drm = DrmBackend::open_card().unwrap();
-    loop {
-        let _ = panic::catch_unwind(AssertUnwindSafe(|| real_main(&mut drm)));
+    // Handled through a signalfd in the main loop instead.
+    let mut mask = SigSet::empty();
+    mask.add(Signal::SIGTERM);
+    mask.add(Signal::SIGINT);
+    mask.add(Signal::SIGHUP);
+    mask.thread_block().unwrap();
+    let signals =
+        SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC).unwrap();
+
+    let config = load_config().unwrap_or_else(|err| {
+        eprintln!("{}, using defaults", err);
+        Config::default()
+    });
+    let mut args = std::env::args().skip(1);
+    if args.next().as_deref() == Some("--headless") {
+        let mut frames = None;
+        let mut dump_dir = None;
+        while let Some(arg) = args.next() {
+            if arg == "--frames" {
+                match args.next().map(|n| n.parse::<u64>()) {
+                    Some(Ok(n)) => frames = Some(n),
+                    _ => {
+                        eprintln!("--frames wants a number of frames");
+                        std::process::exit(1);
+                    }
+                }
+            } else {
+                dump_dir = Some(PathBuf::from(arg));
+            }
+        }
+        // A scripted run doesn't keep scores.
+        let mut dirs: Vec<_> = dump_dir.iter().cloned().collect();
+        if frames.is_none() {
+            dirs.push(highscore::state_dir());
+        }
+        prepare_writable(&config, &dirs);
+        let mut backend = HeadlessBackend::new(dump_dir).unwrap();
+        match frames {
+            Some(frames) => run_scripted(&mut backend, &signals, &config, frames),
+            None => run(&mut backend, &signals, config, None),
+        }
+    } else {
+        prepare_writable(&config, &[highscore::state_dir()]);
+        let mut drm =
+            DrmBackend::open_card(config.card.as_deref(), config.connector.as_deref()).unwrap();
+        let backlight = Backlight::find(Path::new(BACKLIGHT_ROOT))
+            .map_err(|err| eprintln!("Dimming without the backlight: {}", err))
+            .ok();
+        run(&mut drm, &signals, config, backlight);
     }
 }
 
-fn real_main(drm: &mut DrmBackend) {
-    let (height, width) = drm.mode().size();
-    let (db_width, db_height) = drm.fb_info().unwrap().size();
+/// Makes sure `dirs` stay writable once privileges are dropped.
+fn prepare_writable(config: &Config, dirs: &[PathBuf]) {
+    if let Err(err) = prepare_dirs(config, dirs) {
+        eprintln!("Failed to prepare {:?}: {}", dirs, err);
+    }
+}
 
-    let dino_png = include_bytes!("dino.png");
-    let dino_surface = try_load_png(&dino_png[..], 40).unwrap();
+/// Devices that need root to open, so are kept across restarts.
+struct Devices {
+    keyboard: Option<VirtualKeyboard>,
+    backlight: Option<Backlight>,
+}
 
-    let cactus_png = include_bytes!("cactus.png");
-    let cactus_surface = try_load_png(&cactus_png[..], 24).unwrap();
+/// Runs the game until asked to stop, restarting it after a panic.
+fn run<B: DisplayBackend>(
+    backend: &mut B,
+    signals: &SignalFd,
+    mut config: Config,
+    backlight: Option<Backlight>,
+) {
+    let mut config_manager = ConfigManager::new();
+    let mut highscores = HighScores::load();
+    let keyboard = VirtualKeyboard::new()
+        .map_err(|err| eprintln!("Failed to create the virtual keyboard: {}", err))
+        .ok();
+    let mut devices = Devices {
+        keyboard,
+        backlight,
+    };
+    let mut dropped = false;
+    let mut reopen_delay = REOPEN_DELAY;
+    loop {
+        if !backend.connected() {
+            // A crash while the display was gone; don't spin until it's back.
+            if let Err(err) = backend.reopen() {
+                eprintln!("Display not back yet: {}", err);
+                if wait_for_signal(signals, reopen_delay, &mut config, &config_manager) {
+                    break;
+                }
+                reopen_delay = (reopen_delay * 2.0).min(MAX_REOPEN_DELAY);
+                continue;
+            }
+            reopen_delay = REOPEN_DELAY;
+        }
+        // real_main only returns once a shutdown signal arrives.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            real_main(
+                backend,
+                signals,
+                &mut config,
+                &mut config_manager,
+                &mut highscores,
+                &mut devices,
+                &mut dropped,
+            )
+        }));
+        if result.is_ok() {
+            break;
+        }
+    }
+}
 
-    let mut scene = Scene::new(dino_surface, cactus_surface);
+/// How far ahead, in seconds at the current speed, the autopilot jumps at
+/// things.
+const AUTOPILOT_LOOKAHEAD: f64 = 0.3;
+
+/// Input for a dino nobody is playing: taps through every screen that
+/// wants a tap, and jumps at whatever comes close.
+fn autopilot(state: &GameState, tick: u64) -> Inputs {
+    let jump = match state.phase {
+        Phase::Running => {
+            let player = state.player.body.bounds();
+            let reach = player.x + player.width + state.speed() * AUTOPILOT_LOOKAHEAD;
+            state.obstacles.iter().any(|obstacle| {
+                let bounds = obstacle.body.bounds();
+                bounds.x + bounds.width > player.x && bounds.x < reach
+            })
+        }
+        // Let go every other tick, so each press is a fresh tap.
+        _ => tick.is_multiple_of(2),
+    };
+    Inputs {
+        jump,
+        ..Default::default()
+    }
+}
 
+/// Plays `frames` frames on autopilot, as fast as they can be drawn, and
+/// returns, sooner if SIGTERM or SIGINT comes. Needs no input devices, so
+/// runs anywhere, CI included.
+fn run_scripted<B: DisplayBackend>(
+    backend: &mut B,
+    signals: &SignalFd,
+    config: &Config,
+    frames: u64,
+) {
+    let (height, width) = backend.mode();
+    let (db_width, db_height) = backend.fb_info().unwrap();
+    if let Err(err) = drop_privileges(config) {
+        eprintln!("Failed to drop privileges: {}", err);
+        std::process::exit(1);
+    }
+    let mut scene = Scene::new(config);
     let mut surface =
         ImageSurface::create(Format::ARgb32, db_width as i32, db_height as i32).unwrap();
+    // Always the same seed, so two runs can be compared frame by frame.
+    let mut state = GameState::new(width as f64, scene.masks(), config.physics, 0);
+    state.set_ground_color(config.ground_color);
+    let mut last_clips = Vec::new();
+    let mut accumulator = 0.0;
+    let mut tick = 0;
+    for _ in 0..frames {
+        // Nothing to reload for, so SIGHUP is just dropped.
+        while let Ok(Some(info)) = signals.read_signal() {
+            if info.ssi_signo != Signal::SIGHUP as u32 {
+                return;
+            }
+        }
+        accumulator += 1.0 / config.frame_rate as f64;
+        while accumulator >= TICK {
+            state.step(TICK, &autopilot(&state, tick));
+            accumulator -= TICK;
+            tick += 1;
+        }
+        scene.sync(&state);
+        let clips = scene.draw(width as i32, height as i32, &surface, &state, None);
+        present(backend, &mut surface, &clips, &mut last_clips).unwrap();
+    }
+}
+
+/// Epoll tokens for the sources the main loop waits on.
+const INPUT_MAIN: u64 = 0;
+const INPUT_TB: u64 = 1;
+const FRAME_TIMER: u64 = 2;
+const CONFIG: u64 = 3;
+const SIGNAL: u64 = 4;
+const LONG_PRESS: u64 = 5;
+const FLIP: u64 = 6;
+const HOTPLUG: u64 = 7;
+const IDLE: u64 = 8;
+const BURN_IN: u64 = 9;
+const BACKLIGHT: u64 = 10;
+const REOPEN: u64 = 11;
+
+/// Longest stretch of time a single frame will simulate.
+const MAX_FRAME_DELTA: f64 = 0.25;
+
+/// Seconds between looks for a lost display, doubling up to the maximum
+/// while it stays gone. A hotplug event looks straight away.
+const REOPEN_DELAY: f64 = 1.0;
+const MAX_REOPEN_DELAY: f64 = 30.0;
+
+/// What the Touch Bar is currently showing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Game,
+    /// Plain function keys, with DRM master given up so a function row
+    /// daemon can take the display.
+    FunctionRow,
+}
+
+/// How far the bar has gone towards sleep for lack of input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Idle {
+    Awake,
+    Dimmed,
+    /// The display is switched off.
+    Blanked,
+}
+
+impl Idle {
+    /// What comes after this state, and how many seconds after entering it,
+    /// given the configured timeouts.
+    fn next(self, config: &Config) -> Option<(Idle, f64)> {
+        let (dim, blank) = (config.dim_timeout, config.blank_timeout);
+        let dims = dim > 0.0 && (blank <= 0.0 || dim < blank);
+        match self {
+            Idle::Awake if dims => Some((Idle::Dimmed, dim)),
+            Idle::Awake if blank > 0.0 => Some((Idle::Blanked, blank)),
+            Idle::Dimmed if blank > dim => Some((Idle::Blanked, blank - dim)),
+            _ => None,
+        }
+    }
+
+    /// Backlight level for this state.
+    fn backlight(self, config: &Config) -> f64 {
+        match self {
+            Idle::Awake => config.brightness,
+            Idle::Dimmed => config.brightness * config.dim_brightness,
+            Idle::Blanked => 0.0,
+        }
+    }
+}
+
+/// Input from either seat, boiled down to what the main loop cares about.
+enum InputEvent {
+    TouchDown {
+        slot: u32,
+        x: f64,
+    },
+    TouchUp {
+        slot: u32,
+    },
+    TouchCancel,
+    Key(Key, bool),
+    /// The lid was closed.
+    LidClosed,
+}
+
+fn handle_input(
+    libinput: &mut Libinput,
+    digitizer: &mut Option<InputDevice>,
+    width: u16,
+    events: &mut Vec<InputEvent>,
+) {
+    libinput.dispatch().unwrap();
+    for event in &mut *libinput {
+        match event {
+            Event::Device(DeviceEvent::Added(evt))
+                if evt.device().name().contains(" Touch Bar") =>
+            {
+                *digitizer = Some(evt.device());
+            }
+            Event::Touch(te) => {
+                if Some(te.device()) != *digitizer {
+                    continue;
+                }
+                match te {
+                    TouchEvent::Down(dn) => events.push(InputEvent::TouchDown {
+                        slot: dn.seat_slot(),
+                        x: dn.x_transformed(width as u32),
+                    }),
+                    TouchEvent::Up(up) => events.push(InputEvent::TouchUp {
+                        slot: up.seat_slot(),
+                    }),
+                    TouchEvent::Cancel(_) => events.push(InputEvent::TouchCancel),
+                    _ => {}
+                }
+            }
+            Event::Switch(SwitchEvent::Toggle(se))
+                if se.switch() == Some(Switch::Lid) && se.switch_state() == SwitchState::On =>
+            {
+                events.push(InputEvent::LidClosed);
+            }
+            Event::Keyboard(KeyboardEvent::Key(ke)) => {
+                // Our own key presses come back through seat0.
+                if ke.device().name() == uinput::DEVICE_NAME {
+                    continue;
+                }
+                if let Ok(key) = Key::from_code(ke.key() as u16) {
+                    events.push(InputEvent::Key(key, ke.key_state() == KeyState::Pressed));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn send_key(keyboard: Option<&VirtualKeyboard>, key: Key, pressed: bool) {
+    if let Some(keyboard) = keyboard {
+        if let Err(err) = keyboard.key(key, pressed) {
+            eprintln!("Failed to send {:?}: {}", key, err);
+        }
+    }
+}
+
+/// Copies the parts of the frame in `clips` to the display and shows it.
+///
+/// The buffer being drawn into was last on screen a frame ago, so it also
+/// gets what changed in `last_clips`, which is then replaced by `clips`.
+fn present<B: DisplayBackend>(
+    drm: &mut B,
+    surface: &mut ImageSurface,
+    clips: &[ClipRect],
+    last_clips: &mut Vec<ClipRect>,
+) -> Result<()> {
+    if clips.is_empty() {
+        return Ok(());
+    }
+    let stride = surface.stride() as usize;
+    let data = surface.data().unwrap();
+    let mut map = drm.map()?;
+    for clip in clips.iter().chain(last_clips.iter()) {
+        let (x1, x2) = (clip.x1() as usize * 4, clip.x2() as usize * 4);
+        for y in clip.y1() as usize..clip.y2() as usize {
+            let row = y * stride;
+            map[row + x1..row + x2].copy_from_slice(&data[row + x1..row + x2]);
+        }
+    }
+    drop(map);
+    drm.flip(clips)?;
+    *last_clips = clips.to_vec();
+    Ok(())
+}
+
+/// Blocks until the display has caught up with every frame handed to it.
+fn wait_for_flip<B: DisplayBackend>(drm: &mut B) -> Result<()> {
+    while drm.flip_pending() {
+        drm.handle_flip_events()?;
+    }
+    Ok(())
+}
+
+/// Waits up to `seconds` for a signal, for when there's no main loop to
+/// take it. SIGHUP still reloads the config. Returns whether the signal
+/// asked us to stop.
+fn wait_for_signal(
+    signals: &SignalFd,
+    seconds: f64,
+    config: &mut Config,
+    config_manager: &ConfigManager,
+) -> bool {
+    let epoll = Epoll::new(EpollCreateFlags::empty()).unwrap();
+    epoll
+        .add(signals, EpollEvent::new(EpollFlags::EPOLLIN, SIGNAL))
+        .unwrap();
+    let timeout = EpollTimeout::try_from(Duration::from_secs_f64(seconds)).unwrap();
+    let mut events = [EpollEvent::empty(); 1];
+    match epoll.wait(&mut events, timeout) {
+        Ok(_) | Err(Errno::EINTR) => {}
+        Err(err) => panic!("epoll_wait failed: {}", err),
+    }
+    while let Ok(Some(info)) = signals.read_signal() {
+        match Signal::try_from(info.ssi_signo as i32) {
+            Ok(Signal::SIGHUP) => {
+                config_manager.reload(config);
+            }
+            Ok(_) => return true,
+            Err(_) => {}
+        }
+    }
+    false
+}
+
+/// Watches for DRM devices changing, e.g. the Touch Bar connector going
+/// away across a suspend.
+fn hotplug_monitor() -> Option<udev::MonitorSocket> {
+    udev::MonitorBuilder::new()
+        .and_then(|builder| builder.match_subsystem("drm"))
+        .and_then(|builder| builder.listen())
+        .map_err(|err| eprintln!("Failed to watch for display hotplug: {}", err))
+        .ok()
+}
+
+/// Arms `timer` to go off once, `seconds` from now.
+fn set_timeout(timer: &TimerFd, seconds: f64) {
+    timer
+        .set(
+            Expiration::OneShot(TimeSpec::from_duration(Duration::from_secs_f64(seconds))),
+            TimerSetTimeFlags::empty(),
+        )
+        .unwrap();
+}
+
+/// Arms `timer` for whatever comes after `idle`, if anything does.
+fn arm_idle_timer(timer: &TimerFd, idle: Idle, config: &Config) {
+    match idle.next(config) {
+        Some((_, seconds)) => set_timeout(timer, seconds),
+        None => timer.unset().unwrap(),
+    }
+}
+
+/// Dims the bar as far as `idle` calls for: by fading the backlight where
+/// there is one, otherwise by darkening the picture.
+fn show_idle(idle: Idle, scene: &mut Scene, backlight: Option<&mut Backlight>, config: &Config) {
+    match backlight {
+        Some(backlight) => {
+            scene.set_brightness(1.0);
+            backlight.set_resting(config.brightness);
+            let level = idle.backlight(config);
+            if let Err(err) = backlight.ramp_to(level, config.backlight_ramp_time) {
+                eprintln!("Failed to set the backlight: {}", err);
+            }
+        }
+        None if idle == Idle::Dimmed => scene.set_brightness(config.dim_brightness),
+        None => scene.set_brightness(1.0),
+    }
+}
+
+fn set_burn_in_interval(timer: &TimerFd, config: &Config) {
+    if config.pixel_shift == 0 && !config.invert_hud {
+        timer.unset().unwrap();
+        return;
+    }
+    let interval = Duration::from_secs_f64(config.burn_in_interval);
+    timer
+        .set(
+            Expiration::Interval(TimeSpec::from_duration(interval)),
+            TimerSetTimeFlags::empty(),
+        )
+        .unwrap();
+}
+
+fn set_frame_rate(timer: &TimerFd, frame_rate: u32) {
+    let interval = Duration::from_secs_f64(1.0 / frame_rate as f64);
+    timer
+        .set(
+            Expiration::Interval(TimeSpec::from_duration(interval)),
+            TimerSetTimeFlags::empty(),
+        )
+        .unwrap();
+}
+
+fn real_main<B: DisplayBackend>(
+    drm: &mut B,
+    signals: &SignalFd,
+    config: &mut Config,
+    config_manager: &mut ConfigManager,
+    highscores: &mut HighScores,
+    devices: &mut Devices,
+    dropped: &mut bool,
+) {
+    let keyboard = devices.keyboard.as_ref();
+    let mut backlight = devices.backlight.as_mut();
+    let (height, width) = drm.mode();
+    let (db_width, db_height) = drm.fb_info().unwrap();
 
     let mut input_tb = Libinput::new_with_udev(Interface);
     let mut input_main = Libinput::new_with_udev(Interface);
@@ -261,138 +1155,566 @@ fn real_main(drm: &mut DrmBackend) {
     input_main.udev_assign_seat("seat0").unwrap();
     let epoll = Epoll::new(EpollCreateFlags::empty()).unwrap();
     epoll
-        .add(input_main.as_fd(), EpollEvent::new(EpollFlags::EPOLLIN, 0))
+        .add(
+            input_main.as_fd(),
+            EpollEvent::new(EpollFlags::EPOLLIN, INPUT_MAIN),
+        )
         .unwrap();
     epoll
-        .add(input_tb.as_fd(), EpollEvent::new(EpollFlags::EPOLLIN, 1))
+        .add(
+            input_tb.as_fd(),
+            EpollEvent::new(EpollFlags::EPOLLIN, INPUT_TB),
+        )
         .unwrap();
-    let mut dev_name_c = [0 as c_char; 80];
-    let dev_name = "Dynamic Function Row Virtual Input Device".as_bytes();
-    for i in 0..dev_name.len() {
-        dev_name_c[i] = dev_name[i] as c_char;
+    // Everything that needs root is open by now. After a restart the input
+    // devices are reopened through the supplementary groups instead.
+    if !*dropped {
+        if let Err(err) = drop_privileges(config) {
+            eprintln!("Failed to drop privileges: {}", err);
+            std::process::exit(1);
+        }
+        *dropped = true;
     }
 
+    let mut scene = Scene::new(config);
+
+    let mut surface =
+        ImageSurface::create(Format::ARgb32, db_width as i32, db_height as i32).unwrap();
+
     let mut digitizer: Option<InputDevice> = None;
     let mut base_time = TimeStep::new();
 
-    let mut dino_velocity: f64 = 0.0;
-    let da_dino_velocity = &mut dino_velocity as *mut f64;
+    let mut state = GameState::new(
+        width as f64,
+        scene.masks(),
+        config.physics,
+        rand::thread_rng().gen(),
+    );
+    state.set_ground_color(config.ground_color);
+    let mut controls = Controls::new(config.bindings.clone());
+    let mut accumulator = 0.0;
+    let mut recorded = false;
 
-    let dino = scene.drawables.as_mut_ptr_range().start;
-    let da_dino_too = unsafe { &mut *dino } as *mut Drawable;
-    let tree_num = scene.drawables.len() - 1;
-    let mut rng = rand::thread_rng();
+    let frame_timer = TimerFd::new(
+        ClockId::CLOCK_MONOTONIC,
+        TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC,
+    )
+    .unwrap();
+    set_frame_rate(&frame_timer, config.frame_rate);
+    epoll
+        .add(
+            &frame_timer,
+            EpollEvent::new(EpollFlags::EPOLLIN, FRAME_TIMER),
+        )
+        .unwrap();
 
-    let mut input_down_time: Option<Instant> = None;
-    let max_down_time = 120;
+    epoll
+        .add(
+            config_manager.fd(),
+            EpollEvent::new(EpollFlags::EPOLLIN, CONFIG),
+        )
+        .unwrap();
+    epoll
+        .add(signals, EpollEvent::new(EpollFlags::EPOLLIN, SIGNAL))
+        .unwrap();
+    let mut frame_timer_armed = true;
 
-    let trees = unsafe { scene.drawables.as_mut_ptr().add(1) };
+    let long_press_timer = TimerFd::new(
+        ClockId::CLOCK_MONOTONIC,
+        TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC,
+    )
+    .unwrap();
+    epoll
+        .add(
+            &long_press_timer,
+            EpollEvent::new(EpollFlags::EPOLLIN, LONG_PRESS),
+        )
+        .unwrap();
 
-    let player_x_offset = 10.0;
+    let mut mode = Mode::Game;
+    let mut function_row = FunctionRow::new(config.function_keys.clone(), width as f64);
+    let mut shortcuts = Shortcuts::new(
+        config.left_tap_keys.clone(),
+        config.right_tap_keys.clone(),
+        width as f64,
+    );
+    let mut input_events = Vec::new();
 
-    let jump = |elapsed: u128| unsafe {
-        if (*da_dino_too).y == 0.0 {
-            let clamped_elapsed = elapsed.clamp(50, max_down_time) as f64 / max_down_time as f64;
-            (*da_dino_velocity) += 400.0 * clamped_elapsed.powf(1f64 / 2f64);
-            (*da_dino_too).y += 1.0;
-        }
-    };
+    if let Some(fd) = drm.flip_fd() {
+        epoll
+            .add(fd, EpollEvent::new(EpollFlags::EPOLLIN, FLIP))
+            .unwrap();
+    }
+    // A previous run may have left a flip in flight.
+    wait_for_flip(drm).unwrap();
+    let mut last_clips = Vec::new();
+    // A frame came due while the previous one was still waiting for vblank.
+    let mut frame_deferred = false;
 
-    unsafe {
-        (*dino).x = player_x_offset;
+    let hotplug = hotplug_monitor();
+    if let Some(hotplug) = &hotplug {
+        // SAFETY: `fd` is only used for the `add` call, while the socket is
+        // open. Closing it later takes it out of the epoll set by itself.
+        let fd = unsafe { BorrowedFd::borrow_raw(hotplug.as_raw_fd()) };
+        epoll
+            .add(fd, EpollEvent::new(EpollFlags::EPOLLIN, HOTPLUG))
+            .unwrap();
     }
+    // Nothing is drawn until the display comes back.
+    let mut display_lost = false;
+    // The game was running when the display went away.
+    let mut resume_on_return = false;
+    // Dealt with at the top of the next iteration.
+    let mut present_failed = false;
+    let reopen_timer = TimerFd::new(
+        ClockId::CLOCK_MONOTONIC,
+        TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC,
+    )
+    .unwrap();
+    epoll
+        .add(&reopen_timer, EpollEvent::new(EpollFlags::EPOLLIN, REOPEN))
+        .unwrap();
+    let mut reopen_delay = REOPEN_DELAY;
 
-    loop {
-        let delta = base_time.delta();
-        let game_time = base_time.start_time.elapsed().as_secs_f64();
+    let mut suspend = SuspendDetector::new();
 
-        unsafe {
-            dino_velocity -= 30.0 * delta * (*dino).y;
+    let idle_timer = TimerFd::new(
+        ClockId::CLOCK_MONOTONIC,
+        TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC,
+    )
+    .unwrap();
+    epoll
+        .add(&idle_timer, EpollEvent::new(EpollFlags::EPOLLIN, IDLE))
+        .unwrap();
+    let mut idle = Idle::Awake;
+    arm_idle_timer(&idle_timer, idle, config);
 
-            (*dino).y += dino_velocity * delta;
-            if (*dino).y <= 0.0 {
-                (*dino).y = 0.0;
-                dino_velocity = 0.0;
-            }
-            (*dino).needs_redraw = true;
-            
-            let mut offset: f64 = 0.0;
-
-            for tree_index in 0..tree_num {
-                let da_tree = trees.add(tree_index);
-                (*da_tree).x -= 150.0 * delta * game_time.powf(1f64 / 7f64);
-
-                if (*da_tree).x + (*da_tree).width <= 0.0 {
-                    // reset tree
-                    (*da_tree).x = width as f64 + offset;
-                    offset += rng.gen_range(150.0..500.0);
-                    continue;
-                }
+    if let Some(backlight) = backlight.as_deref() {
+        epoll
+            .add(
+                backlight.fd(),
+                EpollEvent::new(EpollFlags::EPOLLIN, BACKLIGHT),
+            )
+            .unwrap();
+    }
+    show_idle(idle, &mut scene, backlight.as_deref_mut(), config);
 
-                if (*da_tree).x <= (*dino).width + player_x_offset
-                    && (*da_tree).x > player_x_offset
-                    && (*dino).y <= (*da_tree).height
-                {
-                    // gameover
-                    return ();
+    let burn_in_timer = TimerFd::new(
+        ClockId::CLOCK_MONOTONIC,
+        TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC,
+    )
+    .unwrap();
+    set_burn_in_interval(&burn_in_timer, config);
+    epoll
+        .add(
+            &burn_in_timer,
+            EpollEvent::new(EpollFlags::EPOLLIN, BURN_IN),
+        )
+        .unwrap();
+
+    let mut events = [EpollEvent::empty(); 12];
+    loop {
+        let timeout = if present_failed {
+            EpollTimeout::ZERO
+        } else {
+            EpollTimeout::NONE
+        };
+        let ready = match epoll.wait(&mut events, timeout) {
+            Ok(ready) => ready,
+            Err(Errno::EINTR) => continue,
+            Err(err) => panic!("epoll_wait failed: {}", err),
+        };
+        let mut frame_due = false;
+        let mut woken = false;
+        let mut reloaded = false;
+        let mut toggle = false;
+        let mut hotplugged = false;
+        let mut reopen_due = false;
+        let mut idle_due = false;
+        // Input that keeps the bar awake.
+        let mut activity = false;
+        if suspend.slept() {
+            // Whatever woke us, the player wasn't there for the time asleep.
+            state.pause();
+        }
+        for event in &events[..ready] {
+            match event.data() {
+                INPUT_MAIN => {
+                    handle_input(&mut input_main, &mut digitizer, width, &mut input_events)
+                }
+                INPUT_TB => handle_input(&mut input_tb, &mut digitizer, width, &mut input_events),
+                CONFIG => reloaded |= config_manager.update(config),
+                SIGNAL => {
+                    while let Ok(Some(info)) = signals.read_signal() {
+                        match Signal::try_from(info.ssi_signo as i32) {
+                            Ok(Signal::SIGHUP) => reloaded |= config_manager.reload(config),
+                            Ok(_) => return,
+                            Err(_) => {}
+                        }
+                    }
+                }
+                FRAME_TIMER => frame_due = true,
+                LONG_PRESS => {
+                    let _ = long_press_timer.wait();
+                    toggle |= mode == Mode::Game;
                 }
+                FLIP => match drm.handle_flip_events() {
+                    Ok(()) => frame_due |= frame_deferred && !drm.flip_pending(),
+                    Err(err) => {
+                        eprintln!("Failed to read display events: {}", err);
+                        present_failed = true;
+                    }
+                },
+                IDLE => {
+                    let _ = idle_timer.wait();
+                    idle_due = true;
+                }
+                BURN_IN => {
+                    let _ = burn_in_timer.wait();
+                    scene.shift_burn_in();
+                }
+                BACKLIGHT => {
+                    if let Some(backlight) = backlight.as_deref_mut() {
+                        if let Err(err) = backlight.tick() {
+                            eprintln!("Failed to set the backlight: {}", err);
+                        }
+                    }
+                }
+                HOTPLUG => {
+                    if let Some(hotplug) = &hotplug {
+                        hotplug.iter().for_each(drop);
+                    }
+                    hotplugged = true;
+                }
+                REOPEN => {
+                    let _ = reopen_timer.wait();
+                    reopen_due = true;
+                }
+                _ => {}
             }
+            // A burn-in shift wakes us too, to get it on screen.
+            woken |= ![FRAME_TIMER, FLIP, IDLE, BACKLIGHT, REOPEN].contains(&event.data());
         }
 
-        scene.draw(width as i32, height as i32, &surface, &base_time);
-        let data = surface.data().unwrap();
-        drm.map().unwrap().as_mut()[..data.len()].copy_from_slice(&data);
-        drm.dirty(&[ClipRect::new(0, 0, height as u16, width as u16)])
-            .unwrap();
+        for event in input_events.drain(..) {
+            match event {
+                InputEvent::Key(key, pressed) => {
+                    activity |= pressed;
+                    // Always tracked, so a key let go in the other mode isn't stuck.
+                    controls.key(key, pressed);
+                    toggle ^= pressed && config.bindings.toggle.contains(&key);
+                }
+                InputEvent::TouchDown { slot, x } => match mode {
+                    // Only wakes the bar up.
+                    Mode::Game if idle == Idle::Blanked => activity = true,
+                    Mode::Game => {
+                        activity = true;
+                        controls.touch_down();
+                        if controls.fingers() == 1 {
+                            set_timeout(&long_press_timer, config.long_press_time);
+                        } else {
+                            long_press_timer.unset().unwrap();
+                        }
+                        let keys = shortcuts.touch_down(slot, x);
+                        if !keys.is_empty() {
+                            // The fingers asked for keys, not a jump.
+                            controls.touch_cancel();
+                            controls.consume();
+                        }
+                        for key in keys {
+                            send_key(keyboard, key, true);
+                        }
+                    }
+                    Mode::FunctionRow => match function_row.touch_down(slot, x) {
+                        Some(Action::Press(key)) => send_key(keyboard, key, true),
+                        Some(Action::Game) => toggle = true,
+                        _ => {}
+                    },
+                },
+                InputEvent::TouchUp { slot } => match mode {
+                    Mode::Game => {
+                        controls.touch_up();
+                        long_press_timer.unset().unwrap();
+                        for key in shortcuts.touch_up(slot) {
+                            send_key(keyboard, key, false);
+                        }
+                    }
+                    Mode::FunctionRow => {
+                        if let Some(Action::Release(key)) = function_row.touch_up(slot) {
+                            send_key(keyboard, key, false);
+                        }
+                    }
+                },
+                InputEvent::LidClosed => state.pause(),
+                InputEvent::TouchCancel => {
+                    controls.touch_cancel();
+                    long_press_timer.unset().unwrap();
+                    for key in function_row.release_all() {
+                        send_key(keyboard, key, false);
+                    }
+                    for key in shortcuts.cancel() {
+                        send_key(keyboard, key, false);
+                    }
+                }
+            }
+        }
 
-        if let Some(down_time) = input_down_time {
-            if down_time.elapsed().as_millis() >= max_down_time {
-                input_down_time = None;
-                let elapsed = down_time.elapsed().as_millis();
-                (jump)(elapsed);
+        if reloaded {
+            scene.apply_config(config);
+            state.physics = config.physics;
+            state.set_masks(scene.masks());
+            state.set_ground_color(config.ground_color);
+            controls.set_bindings(config.bindings.clone());
+            function_row.set_keys(config.function_keys.clone());
+            shortcuts.set_keys(config.left_tap_keys.clone(), config.right_tap_keys.clone());
+            if frame_timer_armed {
+                set_frame_rate(&frame_timer, config.frame_rate);
+            }
+            if idle == Idle::Awake && mode == Mode::Game {
+                arm_idle_timer(&idle_timer, idle, config);
             }
+            show_idle(idle, &mut scene, backlight.as_deref_mut(), config);
+            set_burn_in_interval(&burn_in_timer, config);
         }
 
-        input_tb.dispatch().unwrap();
-        input_main.dispatch().unwrap();
-        for event in &mut input_tb.clone().chain(input_main.clone()) {
-            match event {
-                Event::Device(DeviceEvent::Added(evt)) => {
-                    let dev = evt.device();
-                    if dev.name().contains(" Touch Bar") {
-                        digitizer = Some(dev);
+        if !display_lost && (present_failed || (hotplugged && !drm.connected())) {
+            eprintln!("Lost the display, waiting for it to come back");
+            display_lost = true;
+            present_failed = false;
+            reopen_delay = REOPEN_DELAY;
+            set_timeout(&reopen_timer, reopen_delay);
+            resume_on_return = state.phase == Phase::Running;
+            state.pause();
+            controls.touch_cancel();
+            long_press_timer.unset().unwrap();
+            for key in function_row
+                .release_all()
+                .into_iter()
+                .chain(shortcuts.cancel())
+            {
+                send_key(keyboard, key, false);
+            }
+            // Whatever handoff was going on ended with the old card.
+            mode = Mode::Game;
+            idle_timer.unset().unwrap();
+            frame_timer.unset().unwrap();
+            frame_timer_armed = false;
+            if let Some(fd) = drm.flip_fd() {
+                let _ = epoll.delete(fd);
+            }
+        }
+        if display_lost && (hotplugged || reopen_due) {
+            match drm.reopen() {
+                Ok(()) => {
+                    if drm.mode() != (height, width) {
+                        // Everything here was sized for the old mode.
+                        panic!("Display came back with a different mode");
+                    }
+                    if let Some(fd) = drm.flip_fd() {
+                        epoll
+                            .add(fd, EpollEvent::new(EpollFlags::EPOLLIN, FLIP))
+                            .unwrap();
                     }
+                    display_lost = false;
+                    reopen_timer.unset().unwrap();
+                    // The reopened display is on and at full brightness.
+                    idle = Idle::Awake;
+                    show_idle(idle, &mut scene, backlight.as_deref_mut(), config);
+                    arm_idle_timer(&idle_timer, idle, config);
+                    last_clips.clear();
+                    frame_deferred = false;
+                    scene.invalidate();
+                    if resume_on_return {
+                        state.resume();
+                    }
+                    woken = true;
                 }
-                Event::Touch(te) => {
-                    if Some(te.device()) != digitizer {
-                        continue;
+                Err(err) => {
+                    eprintln!("Display not back yet: {}", err);
+                    if reopen_due {
+                        reopen_delay = (reopen_delay * 2.0).min(MAX_REOPEN_DELAY);
                     }
-                    match te {
-                        TouchEvent::Down(_dn) => {
-                            input_down_time = Some(Instant::now());
-                        }
-                        TouchEvent::Motion(_mtn) => {
-                            if input_down_time.is_none() {
-                                input_down_time = Some(Instant::now());
-                            }
+                    set_timeout(&reopen_timer, reopen_delay);
+                }
+            }
+        }
+        toggle &= !display_lost;
+
+        if toggle {
+            match mode {
+                Mode::Game => {
+                    long_press_timer.unset().unwrap();
+                    state.pause();
+                    // The finger that asked for this is not a jump.
+                    controls.touch_cancel();
+                    for key in shortcuts.cancel() {
+                        send_key(keyboard, key, false);
+                    }
+                    // Sleeping the bar is up to whoever has it next.
+                    idle_timer.unset().unwrap();
+                    if idle == Idle::Blanked {
+                        drm.set_active(true).unwrap();
+                    }
+                    idle = Idle::Awake;
+                    show_idle(idle, &mut scene, backlight.as_deref_mut(), config);
+                    wait_for_flip(drm).unwrap();
+                    scene.draw_function_row(height as i32, &surface, &function_row);
+                    present(
+                        drm,
+                        &mut surface,
+                        &[ClipRect::new(0, 0, height, width)],
+                        &mut last_clips,
+                    )
+                    .unwrap();
+                    // Don't hand over the display with our flip still in flight.
+                    wait_for_flip(drm).unwrap();
+                    frame_deferred = false;
+                    if let Err(err) = drm.drop_master() {
+                        eprintln!("Failed to hand the display over: {}", err);
+                    }
+                    frame_timer.unset().unwrap();
+                    frame_timer_armed = false;
+                    mode = Mode::FunctionRow;
+                }
+                Mode::FunctionRow => match drm.acquire_master() {
+                    Ok(()) => {
+                        for key in function_row.release_all() {
+                            send_key(keyboard, key, false);
                         }
-                        TouchEvent::Up(_up) => {
-                            if let Some(down_time) = input_down_time {
-                                input_down_time = None;
-                                let elapsed = down_time.elapsed().as_millis();
-                                (jump)(elapsed);
+                        // Presses meant for the function row are not game input.
+                        controls.consume();
+                        scene.invalidate();
+                        mode = Mode::Game;
+                        arm_idle_timer(&idle_timer, idle, config);
+                    }
+                    Err(err) => eprintln!("Failed to take the display back: {}", err),
+                },
+            }
+        }
+
+        if mode == Mode::Game && !display_lost {
+            if activity && idle != Idle::Awake {
+                if idle == Idle::Blanked {
+                    if let Err(err) = drm.set_active(true) {
+                        eprintln!("Failed to switch the display back on: {}", err);
+                    }
+                }
+                idle = Idle::Awake;
+                show_idle(idle, &mut scene, backlight.as_deref_mut(), config);
+                scene.invalidate();
+                woken = true;
+            }
+            if activity {
+                arm_idle_timer(&idle_timer, idle, config);
+            } else if idle_due {
+                if let Some((next, _)) = idle.next(config) {
+                    idle = next;
+                    arm_idle_timer(&idle_timer, idle, config);
+                    show_idle(idle, &mut scene, backlight.as_deref_mut(), config);
+                    match next {
+                        Idle::Awake => {}
+                        // Get a frame out to show it, in case that was the picture.
+                        Idle::Dimmed => woken = true,
+                        Idle::Blanked => {
+                            state.pause();
+                            frame_timer.unset().unwrap();
+                            frame_timer_armed = false;
+                            frame_deferred = false;
+                            let blanked = wait_for_flip(drm).and_then(|_| drm.set_active(false));
+                            if let Err(err) = blanked {
+                                eprintln!("Failed to switch the display off: {}", err);
                             }
                         }
-                        _ => {}
                     }
                 }
-                _ => {}
             }
         }
 
-        let sleep_time = (1 / 144) as f64 - delta;
-        if sleep_time > 0.0 {
-            thread::sleep(Duration::from_secs(sleep_time as u64));
+        if woken
+            && !frame_timer_armed
+            && mode == Mode::Game
+            && !display_lost
+            && idle != Idle::Blanked
+        {
+            // Nothing moved while idle, so don't let the sim catch up on it.
+            base_time.reset();
+            set_frame_rate(&frame_timer, config.frame_rate);
+            frame_timer_armed = true;
+        }
+        if !frame_due || mode != Mode::Game || display_lost || idle == Idle::Blanked {
+            continue;
+        }
+        // Drain the expiration count; overruns just mean a longer delta.
+        let _ = frame_timer.wait();
+        frame_deferred = drm.flip_pending();
+        if frame_deferred {
+            continue;
+        }
+
+        if !drm.powered() {
+            // Someone switched the bar off under us. A flip would fail on the
+            // dead CRTC and look like a lost display, so sit still until
+            // something wakes us and check again.
+            state.pause();
+            frame_timer.unset().unwrap();
+            frame_timer_armed = false;
+            continue;
+        }
+        accumulator += base_time.delta().min(MAX_FRAME_DELTA);
+        while accumulator >= TICK {
+            state.step(TICK, &controls.inputs());
+            controls.consume();
+            accumulator -= TICK;
+
+            if let Phase::GameOver { score } = state.phase {
+                if !recorded {
+                    recorded = true;
+                    highscores.record(score, Local::now());
+                }
+            } else {
+                recorded = false;
+            }
+        }
+        scene.sync(&state);
+        let clips = scene.draw(
+            width as i32,
+            height as i32,
+            &surface,
+            &state,
+            highscores.best(),
+        );
+        if let Err(err) = present(drm, &mut surface, &clips, &mut last_clips) {
+            eprintln!("Failed to present a frame: {}", err);
+            present_failed = true;
+        }
+
+        if state.is_idle() {
+            frame_timer.unset().unwrap();
+            frame_timer_armed = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burn_in_keeps_the_ground_on_the_bar() {
+        let mut burn_in = BurnIn {
+            pixel_shift: 2,
+            invert_hud: false,
+            step: 0,
+        };
+        let mut seen = Vec::new();
+        for step in 0..15 {
+            burn_in.step = step;
+            let (dx, dy) = burn_in.offset();
+            assert!((-2.0..=2.0).contains(&dx) && (-2.0..=0.0).contains(&dy));
+            assert!(!seen.contains(&(dx, dy)), "{:?} twice", (dx, dy));
+            seen.push((dx, dy));
         }
+        burn_in.step = 15;
+        assert_eq!(burn_in.offset(), (-2.0, 0.0));
     }
 }
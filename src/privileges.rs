@@ -0,0 +1,107 @@
+use crate::config::Config;
+use anyhow::{anyhow, Result};
+use nix::unistd::{geteuid, Gid, Group, Uid, User};
+use privdrop::PrivDrop;
+use std::{
+    fs,
+    io::ErrorKind,
+    os::unix::fs::{chown, MetadataExt},
+    path::PathBuf,
+};
+
+/// Gives up root for the user and groups in `config`. Call once every
+/// device that needs root is open.
+///
+/// Does nothing when no user is configured, or when not running as root
+/// to begin with, e.g. a headless run from a shell.
+pub fn drop_privileges(config: &Config) -> Result<()> {
+    if config.user.is_empty() || !geteuid().is_root() {
+        return Ok(());
+    }
+    // Missing groups just mean some devices can't be reopened, which beats
+    // not starting at all.
+    let mut groups = Vec::new();
+    for group in &config.groups {
+        if group.parse::<u32>().is_ok() || Group::from_name(group)?.is_some() {
+            groups.push(group.as_str());
+        } else {
+            eprintln!("No such group, leaving it out: {}", group);
+        }
+    }
+    let mut privdrop = PrivDrop::default()
+        .user(&config.user)
+        .group_list(&groups)
+        .fallback_to_ids_if_names_are_numeric();
+    if !config.group.is_empty() {
+        privdrop = privdrop.group(&config.group);
+    }
+    if let Some(root) = &config.chroot {
+        privdrop = privdrop.chroot(root);
+    }
+    privdrop.apply()?;
+    Ok(())
+}
+
+/// The uid and gid `PrivDrop` ends up with for `config`: names first, then
+/// numbers. A numeric user has no primary group to go by, so without a
+/// `Group` the gid stays as it is.
+fn target_ids(config: &Config) -> Result<(Uid, Option<Gid>)> {
+    let (uid, mut gid) = match User::from_name(&config.user)? {
+        Some(user) => (user.uid, Some(user.gid)),
+        None => match config.user.parse() {
+            Ok(id) => (Uid::from_raw(id), None),
+            Err(_) => return Err(anyhow!("No such user: {}", config.user)),
+        },
+    };
+    if !config.group.is_empty() {
+        gid = Some(match Group::from_name(&config.group)? {
+            Some(group) => group.gid,
+            None => match config.group.parse() {
+                Ok(id) => Gid::from_raw(id),
+                Err(_) => return Err(anyhow!("No such group: {}", config.group)),
+            },
+        });
+    }
+    Ok((uid, gid))
+}
+
+/// Creates `dirs` for the user `drop_privileges` switches to, so it can
+/// still write there. Needed wherever nothing like systemd's
+/// `StateDirectory=` has set them up already. With a chroot they are made
+/// inside it, where the user will see them.
+///
+/// Only directories made here are handed over. One that already exists is
+/// left as it is, or `--headless /etc` would give away /etc.
+///
+/// Does nothing in the same cases `drop_privileges` doesn't.
+pub fn prepare_dirs(config: &Config, dirs: &[PathBuf]) -> Result<()> {
+    if config.user.is_empty() || !geteuid().is_root() {
+        return Ok(());
+    }
+    let (uid, gid) = target_ids(config)?;
+    for dir in dirs {
+        let dir = match &config.chroot {
+            // Relative paths too, since the chroot starts out in its root.
+            Some(root) => root.join(dir.strip_prefix("/").unwrap_or(dir)),
+            None => dir.clone(),
+        };
+        match fs::metadata(&dir) {
+            Ok(metadata) => {
+                if metadata.uid() != uid.as_raw() {
+                    eprintln!(
+                        "{} already exists and isn't {}'s, leaving it alone",
+                        dir.display(),
+                        config.user
+                    );
+                }
+                continue;
+            }
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(anyhow!("{}: {}", dir.display(), err)),
+        }
+        fs::create_dir_all(&dir).map_err(|e| anyhow!("{}: {}", dir.display(), e))?;
+        chown(&dir, Some(uid.as_raw()), gid.map(Gid::as_raw))
+            .map_err(|e| anyhow!("{}: {}", dir.display(), e))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,70 @@
+use input_linux::Key;
+use std::collections::HashMap;
+
+/// Two-finger taps on the outer thirds of the bar, so the keys it replaced
+/// are still there while the game is up.
+///
+/// The keys go down when the second finger lands and come back up as soon
+/// as either finger lifts.
+pub struct Shortcuts {
+    left: Vec<Key>,
+    right: Vec<Key>,
+    width: f64,
+    /// Where each touch slot landed.
+    touches: HashMap<u32, f64>,
+    held: Vec<Key>,
+}
+
+impl Shortcuts {
+    pub fn new(left: Vec<Key>, right: Vec<Key>, width: f64) -> Shortcuts {
+        Shortcuts {
+            left,
+            right,
+            width,
+            touches: HashMap::new(),
+            held: Vec::new(),
+        }
+    }
+
+    /// Takes effect on the next tap; keys already held stay held.
+    pub fn set_keys(&mut self, left: Vec<Key>, right: Vec<Key>) {
+        self.left = left;
+        self.right = right;
+    }
+
+    /// Returns the keys to press, in order.
+    pub fn touch_down(&mut self, slot: u32, x: f64) -> Vec<Key> {
+        self.touches.insert(slot, x);
+        if self.touches.len() != 2 || !self.held.is_empty() {
+            return Vec::new();
+        }
+        let third = self.width / 3.0;
+        let keys = if self.touches.values().all(|&x| x < third) {
+            &self.left
+        } else if self.touches.values().all(|&x| x >= self.width - third) {
+            &self.right
+        } else {
+            return Vec::new();
+        };
+        self.held = keys.clone();
+        self.held.clone()
+    }
+
+    /// Returns the keys to release, in order.
+    pub fn touch_up(&mut self, slot: u32) -> Vec<Key> {
+        self.touches.remove(&slot);
+        self.release()
+    }
+
+    /// Forgets every finger, returning the keys to release.
+    pub fn cancel(&mut self) -> Vec<Key> {
+        self.touches.clear();
+        self.release()
+    }
+
+    fn release(&mut self) -> Vec<Key> {
+        let mut keys = std::mem::take(&mut self.held);
+        keys.reverse();
+        keys
+    }
+}
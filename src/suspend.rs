@@ -0,0 +1,37 @@
+use nix::time::{clock_gettime, ClockId};
+use std::time::Duration;
+
+/// How far the clocks have to drift apart to count as a suspend rather
+/// than noise between the two reads.
+const THRESHOLD: Duration = Duration::from_millis(500);
+
+/// Notices the machine having been asleep.
+///
+/// `CLOCK_BOOTTIME` keeps counting through a suspend and
+/// `CLOCK_MONOTONIC` does not, so the gap between them grows by however
+/// long the machine slept.
+pub struct SuspendDetector {
+    offset: Duration,
+}
+
+fn clock_offset() -> Duration {
+    let boot = Duration::from(clock_gettime(ClockId::CLOCK_BOOTTIME).unwrap());
+    let monotonic = Duration::from(clock_gettime(ClockId::CLOCK_MONOTONIC).unwrap());
+    boot.saturating_sub(monotonic)
+}
+
+impl SuspendDetector {
+    pub fn new() -> SuspendDetector {
+        SuspendDetector {
+            offset: clock_offset(),
+        }
+    }
+
+    /// Whether the machine slept since the last call.
+    pub fn slept(&mut self) -> bool {
+        let offset = clock_offset();
+        let slept = offset > self.offset + THRESHOLD;
+        self.offset = offset;
+        slept
+    }
+}
@@ -0,0 +1,53 @@
+use anyhow::Result;
+use input_linux::{EventKind, InputId, Key, SynchronizeKind, UInputHandle};
+use input_linux_sys::{input_event, timeval};
+use std::fs::{File, OpenOptions};
+
+pub const DEVICE_NAME: &str = "Dynamic Function Row Virtual Input Device";
+
+/// The keyboard that key presses made on the Touch Bar come out of.
+pub struct VirtualKeyboard {
+    uinput: UInputHandle<File>,
+}
+
+impl VirtualKeyboard {
+    pub fn new() -> Result<VirtualKeyboard> {
+        let uinput = UInputHandle::new(OpenOptions::new().write(true).open("/dev/uinput")?);
+        uinput.set_evbit(EventKind::Key)?;
+        // Everything is enabled up front so that key bindings can change
+        // with the config without recreating the device.
+        for key in Key::iter().filter(Key::is_key) {
+            uinput.set_keybit(key)?;
+        }
+        uinput.create(
+            &InputId {
+                bustype: 0x19,
+                vendor: 0x1209,
+                product: 0x316E,
+                version: 1,
+            },
+            DEVICE_NAME.as_bytes(),
+            0,
+            &[],
+        )?;
+        Ok(VirtualKeyboard { uinput })
+    }
+
+    fn emit(&self, kind: EventKind, code: u16, value: i32) -> Result<()> {
+        self.uinput.write(&[input_event {
+            time: timeval {
+                tv_sec: 0,
+                tv_usec: 0,
+            },
+            type_: kind as u16,
+            code,
+            value,
+        }])?;
+        Ok(())
+    }
+
+    pub fn key(&self, key: Key, pressed: bool) -> Result<()> {
+        self.emit(EventKind::Key, key as u16, pressed as i32)?;
+        self.emit(EventKind::Synchronize, SynchronizeKind::Report as u16, 0)
+    }
+}
@@ -0,0 +1,74 @@
+//! Runs the daemon with no Touch Bar and no input devices, the way CI can.
+
+use nix::unistd::geteuid;
+use std::{
+    env, fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// A fresh directory for one test to dump frames into.
+fn dump_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("dinobar-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+fn frames(dir: &Path) -> Vec<PathBuf> {
+    let mut frames: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    frames.sort();
+    frames
+}
+
+fn run_scripted(frames: u64, dir: &Path) {
+    let status = Command::new(env!("CARGO_BIN_EXE_tiny-dfr"))
+        .args(["--headless", "--frames", &frames.to_string()])
+        .arg(dir)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+/// Width and height from a PNG's header.
+fn png_size(path: &Path) -> (u32, u32) {
+    let data = fs::read(path).unwrap();
+    assert_eq!(&data[1..4], b"PNG", "{} is not a PNG", path.display());
+    let width = u32::from_be_bytes(data[16..20].try_into().unwrap());
+    let height = u32::from_be_bytes(data[20..24].try_into().unwrap());
+    (width, height)
+}
+
+#[test]
+fn scripted_run_dumps_frames() {
+    let dir = dump_dir("frames");
+    run_scripted(120, &dir);
+    let frames = frames(&dir);
+    // Frames where nothing changed aren't presented, so aren't dumped.
+    assert!(!frames.is_empty() && frames.len() <= 120);
+    for frame in &frames {
+        assert_eq!(png_size(frame), (2170, 60));
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Started as root, the frames must already be written by someone else.
+/// Assumes the configured `User` isn't root, as it is by default.
+#[test]
+fn scripted_run_drops_root() {
+    if !geteuid().is_root() {
+        eprintln!("Not root, nothing to drop");
+        return;
+    }
+    let dir = dump_dir("privileges");
+    run_scripted(10, &dir);
+    let frames = frames(&dir);
+    assert!(!frames.is_empty());
+    for frame in &frames {
+        assert_ne!(fs::metadata(frame).unwrap().uid(), 0, "{}", frame.display());
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}